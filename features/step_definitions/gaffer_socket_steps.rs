@@ -37,7 +37,7 @@ pub fn register_steps(c: &mut CucumberRegistrar<SocketWorld>) {
         world.gaffer_sockets.get_mut(&own_port).ok_or(InvokeResponse::fail_from_str("No socket at that port"))
           .and_then(|socket| {
             let addr = ("127.0.0.1", remote_port).to_single_socket_addr().unwrap();
-            let packet = GafferPacket { addr: addr, payload: payload };
+            let packet = GafferPacket { addr: addr, payload: payload, message_id: None };
             socket.send(packet)
               .map_err(|_| InvokeResponse::fail_from_str("Could not send packet"))
           })
@@ -63,17 +63,17 @@ pub fn register_steps(c: &mut CucumberRegistrar<SocketWorld>) {
   Then!(c, "^the gaffer socket on (\\d+) receives a payload from (\\d+) matching:$", |_, world: &mut SocketWorld, (own_port, remote_port, payload_details): (u16, u16, Vec<Vec<String>>)|{
     match GafferPayload::from_table(payload_details) {
       Err(err) => err,
-      Ok(mut payload) => {
+      Ok(payload) => {
         world.gaffer_sockets.get_mut(&own_port).ok_or(InvokeResponse::fail_from_str("No socket at that port"))
           .and_then(|socket| {
             socket.recv()
               .map_err(|_| InvokeResponse::fail_from_str("Could not receive packet"))
           })
           .map(|recv_packet| {
-            payload.resize(1016, 0);
             let expected_packet = GafferPacket {
               addr: ("127.0.0.1", remote_port).to_single_socket_addr().unwrap(),
-              payload: payload
+              payload: payload,
+              message_id: None
             };
             InvokeResponse::check_eq(expected_packet, recv_packet)
           })
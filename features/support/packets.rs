@@ -1,6 +1,7 @@
 use gaffer_udp::{
   CompleteGafferPacket,
-  GafferPayload
+  GafferPayload,
+  PacketKind
 };
 
 use cucumber::InvokeResponse;
@@ -46,8 +47,8 @@ impl FromTable for CompleteGafferPacket {
             let val = try!(u8::from_str(num).map_err(|_| {InvokeResponse::fail_from_str("Could not convert a value in payload to u8")}));
             building_payload.push(val);
           }
-          building_payload.resize(1016, 0);
-          payload = Some(building_payload);
+          building_payload.resize(1015, 0);
+          payload = Some(building_payload.into());
         },
         _ => return Err(InvokeResponse::fail_from_str("Unknown field type in CompleteGafferPacket table"))
       }
@@ -58,9 +59,11 @@ impl FromTable for CompleteGafferPacket {
     }
 
     Ok(CompleteGafferPacket {
+      kind: PacketKind::Data,
       seq: seq.unwrap(),
       ack_seq: ack_seq.unwrap(),
       ack_field: ack_field.unwrap(),
+      message_id: seq.unwrap(),
       payload: payload.unwrap()
     })
   }
@@ -76,9 +79,9 @@ impl FromTable for GafferPayload {
     for value in table.get(0).unwrap().into_iter() {
       payload.push(try!(u8::from_str(&value).map_err(|_| InvokeResponse::fail_from_str("Could not convert an entry in GafferPayload to u8"))));
     }
-    payload.resize(1016, 0);
+    payload.resize(1015, 0);
 
-    Ok(payload)
+    Ok(payload.into())
   }
 }
 
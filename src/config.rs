@@ -0,0 +1,290 @@
+use std::io;
+
+use std::net::{SocketAddr, UdpSocket};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+use packet::GAFFER_DATAGRAM_SIZE;
+
+use connection::DEDUP_WINDOW_SIZE;
+
+use limit::RateLimit;
+
+use replay::REPLAY_WINDOW_SIZE;
+
+/// Options for binding a gaffer socket and running the protocol over it
+///
+/// Socket options left as `None` keep the OS default.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GafferConfig {
+  /// SO_RCVBUF, in bytes
+  pub recv_buffer_size: Option<usize>,
+  /// SO_SNDBUF, in bytes
+  pub send_buffer_size: Option<usize>,
+  /// IP_TTL, or the unicast hop limit for IPv6
+  pub ttl: Option<u32>,
+  /// DSCP code point (0-63), written to the TOS or traffic class byte
+  pub dscp: Option<u8>,
+  /// IPV6_V6ONLY for IPv6 addresses, `Some(false)` for a dual-stack socket
+  pub only_v6: Option<bool>,
+  /// SO_REUSEADDR
+  pub reuse_address: bool,
+  /// SO_REUSEPORT, unix only
+  pub reuse_port: bool,
+  /// Largest datagram the protocol sends, header included, `GAFFER_DATAGRAM_SIZE` by default
+  pub mtu: usize,
+  /// Probe each peer for the largest datagram the path carries, see `connection::PathMtu`
+  ///
+  /// Sets the don't-fragment bit on Linux, so oversized probes are lost rather than fragmented.
+  pub path_mtu_discovery: bool,
+  /// Datagram size, header included, assumed to reach every peer
+  pub path_mtu_min: usize,
+  /// Largest datagram size probed for, receive buffers grow to fit it
+  pub path_mtu_max: usize,
+  /// Idle buffers kept for reuse by encoding and decoding, 0 allocates every time
+  pub buffer_pool_size: usize,
+  /// Furthest a received sequence number may run ahead of the newest one seen
+  ///
+  /// Keeps a single forged packet from wiping the ack state. A peer that loses more packets
+  /// than this in a row is caught up with once `connection::RESYNC_PACKETS` datagrams in a row
+  /// arrive past the gap.
+  pub max_sequence_jump: u16,
+  /// Sequence numbers behind the newest one still accepted per peer, once each
+  ///
+  /// Anything older is refused as a possible replay, so this bounds how late a reordered
+  /// datagram may arrive. Rounded up to a multiple of 64.
+  pub replay_window: u64,
+  /// Message ids remembered per peer to drop resent payloads that already arrived
+  ///
+  /// A resend older than this many newer messages is delivered, even if its first copy arrived.
+  pub dedup_window: u64,
+  /// Inbound traffic allowed from each address, anything over it is dropped before processing
+  pub peer_rate_limit: Option<RateLimit>,
+  /// Inbound traffic allowed from all addresses together
+  pub global_rate_limit: Option<RateLimit>,
+  /// Most addresses held to `peer_rate_limit` at once
+  ///
+  /// Keeps a flood from many addresses from growing the table without bound. Addresses past it
+  /// are held to `global_rate_limit` alone, and refused if there is none.
+  pub max_rate_limited_peers: usize,
+  /// Raise `GafferEvent::PeerAbusive` when an address goes over `peer_rate_limit`
+  pub report_abuse: bool,
+  /// Most peers kept at once, `None` for no limit
+  ///
+  /// Only new peers reaching us are held to it. Connections started by sending to an address
+  /// count toward it but are never refused.
+  pub max_connections: Option<usize>,
+  /// What happens to a new peer once `max_connections` is reached
+  pub when_full: FullPolicy,
+  /// Refuse traffic to and from peers without keys, see `GafferState::set_keys`
+  #[cfg(feature = "crypto")]
+  pub require_encryption: bool,
+  /// Key shared with the backend issuing connect tokens, see `TokenGenerator`
+  ///
+  /// When set, only clients holding a valid token are let in and all traffic is encrypted.
+  #[cfg(feature = "crypto")]
+  pub connect_token_key: Option<[u8; 32]>,
+  /// Secret for challenge cookies, see `GafferState::process_datagram`
+  ///
+  /// When set, a new address only gets a `Connection` once it echoes back a cookie sent to it.
+  #[cfg(feature = "crypto")]
+  pub cookie_key: Option<[u8; 32]>,
+}
+
+impl GafferConfig {
+  pub fn new() -> GafferConfig {
+    GafferConfig {
+      recv_buffer_size: None,
+      send_buffer_size: None,
+      ttl: None,
+      dscp: None,
+      only_v6: None,
+      reuse_address: false,
+      reuse_port: false,
+      mtu: GAFFER_DATAGRAM_SIZE,
+      path_mtu_discovery: false,
+      path_mtu_min: 1200,
+      path_mtu_max: 8972,
+      buffer_pool_size: 256,
+      max_sequence_jump: 1024,
+      replay_window: REPLAY_WINDOW_SIZE,
+      dedup_window: DEDUP_WINDOW_SIZE,
+      peer_rate_limit: None,
+      global_rate_limit: None,
+      max_rate_limited_peers: 16384,
+      report_abuse: false,
+      max_connections: None,
+      when_full: FullPolicy::Reject,
+      #[cfg(feature = "crypto")]
+      require_encryption: false,
+      #[cfg(feature = "crypto")]
+      connect_token_key: None,
+      #[cfg(feature = "crypto")]
+      cookie_key: None,
+    }
+  }
+
+  /// Largest datagram a socket with this config expects to receive
+  pub fn max_datagram_size(&self) -> usize {
+    if self.path_mtu_discovery {
+      self.mtu.max(self.path_mtu_max)
+    } else {
+      self.mtu
+    }
+  }
+
+  /// Checks for options that cannot work together
+  ///
+  /// A rate limit has to let through at least one of the largest datagrams a second, its
+  /// buckets never hold more than a second's worth. `FullPolicy::EvictLeastRecent` needs
+  /// `cookie_key` or `connect_token_key`, it never evicts without them.
+  pub fn validate(&self) -> io::Result<()> {
+    if self.when_full == FullPolicy::EvictLeastRecent && !self.proves_addresses() {
+      return Err(io::Error::new(io::ErrorKind::InvalidInput, "evicting peers needs a cookie or connect token key"));
+    }
+    for limit in self.peer_rate_limit.iter().chain(self.global_rate_limit.iter()) {
+      if limit.packets_per_sec == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "rate limit lets no packets through"));
+      }
+      if (limit.bytes_per_sec as usize) < self.max_datagram_size() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "rate limit is below the largest datagram size"));
+      }
+    }
+    Ok(())
+  }
+
+  /// Whether new peers have to prove they own their address before they get a connection
+  #[cfg(feature = "crypto")]
+  fn proves_addresses(&self) -> bool {
+    self.cookie_key.is_some() || self.connect_token_key.is_some()
+  }
+
+  #[cfg(not(feature = "crypto"))]
+  fn proves_addresses(&self) -> bool {
+    false
+  }
+
+  /// Binds a udp socket to `addr` with these options applied, once they pass `validate`
+  pub fn bind_udp(&self, addr: SocketAddr) -> io::Result<UdpSocket> {
+    self.validate()?;
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+
+    if let Some(size) = self.recv_buffer_size {
+      socket.set_recv_buffer_size(size)?;
+    }
+    if let Some(size) = self.send_buffer_size {
+      socket.set_send_buffer_size(size)?;
+    }
+    if self.reuse_address {
+      socket.set_reuse_address(true)?;
+    }
+    if self.reuse_port {
+      set_reuse_port(&socket)?;
+    }
+
+    match addr {
+      SocketAddr::V4(_) => {
+        if let Some(ttl) = self.ttl {
+          socket.set_ttl(ttl)?;
+        }
+        if let Some(dscp) = self.dscp {
+          socket.set_tos(tos_byte(dscp)?)?;
+        }
+      },
+      SocketAddr::V6(_) => {
+        if let Some(only_v6) = self.only_v6 {
+          socket.set_only_v6(only_v6)?;
+        }
+        if let Some(ttl) = self.ttl {
+          socket.set_unicast_hops_v6(ttl)?;
+        }
+        if let Some(dscp) = self.dscp {
+          set_tclass_v6(&socket, tos_byte(dscp)?)?;
+        }
+      },
+    }
+
+    if self.path_mtu_discovery {
+      set_dont_fragment(&socket, addr)?;
+    }
+
+    socket.bind(&addr.into())?;
+    Ok(socket.into())
+  }
+}
+
+/// How a socket with `GafferConfig::max_connections` peers treats another one
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FullPolicy {
+  /// Turn the new peer away with a `Full` reply
+  Reject,
+  /// Forget the connection that has gone longest without receiving anything to make room
+  ///
+  /// Only for peers that proved they own their address, by echoing a challenge cookie or with
+  /// a connect token, see `GafferConfig::cookie_key` and `GafferConfig::connect_token_key`;
+  /// anyone could otherwise push out every real peer with spoofed source addresses. Binding
+  /// with neither configured is refused, see `GafferConfig::validate`.
+  EvictLeastRecent,
+}
+
+impl Default for GafferConfig {
+  fn default() -> GafferConfig {
+    GafferConfig::new()
+  }
+}
+
+fn tos_byte(dscp: u8) -> io::Result<u32> {
+  if dscp > 63 {
+    return Err(io::Error::new(io::ErrorKind::InvalidInput, "DSCP is a 6 bit value"));
+  }
+  Ok((dscp as u32) << 2)
+}
+
+#[cfg(unix)]
+fn set_reuse_port(socket: &Socket) -> io::Result<()> {
+  socket.set_reuse_port(true)
+}
+
+#[cfg(not(unix))]
+fn set_reuse_port(_socket: &Socket) -> io::Result<()> {
+  Err(io::Error::new(io::ErrorKind::Unsupported, "SO_REUSEPORT is not available on this platform"))
+}
+
+#[cfg(target_os = "linux")]
+fn set_dont_fragment(socket: &Socket, addr: SocketAddr) -> io::Result<()> {
+  use std::os::unix::io::AsRawFd;
+
+  // PROBE sets DF without letting the kernel's own path MTU estimate refuse larger sends
+  let (level, name, value) = match addr {
+    SocketAddr::V4(_) => (libc::IPPROTO_IP, libc::IP_MTU_DISCOVER, libc::IP_PMTUDISC_PROBE),
+    SocketAddr::V6(_) => (libc::IPPROTO_IPV6, libc::IPV6_MTU_DISCOVER, libc::IPV6_PMTUDISC_PROBE),
+  };
+  let res = unsafe {
+    libc::setsockopt(
+      socket.as_raw_fd(),
+      level,
+      name,
+      &value as *const libc::c_int as *const libc::c_void,
+      ::std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+    )
+  };
+  if res < 0 {
+    return Err(io::Error::last_os_error());
+  }
+  Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_dont_fragment(_socket: &Socket, _addr: SocketAddr) -> io::Result<()> {
+  Ok(())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "freebsd"))]
+fn set_tclass_v6(socket: &Socket, tclass: u32) -> io::Result<()> {
+  socket.set_tclass_v6(tclass)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "freebsd")))]
+fn set_tclass_v6(_socket: &Socket, _tclass: u32) -> io::Result<()> {
+  Err(io::Error::new(io::ErrorKind::Unsupported, "IPv6 traffic class is not available on this platform"))
+}
@@ -1,35 +1,184 @@
 use std::collections::HashMap;
 
+use std::time::Instant;
+
 use itertools::Itertools;
 
-use packet::GafferPacket;
+#[cfg(feature = "crypto")]
+use crypto::PacketCipher;
+
+use packet::{GafferPacket, GAFFER_DATAGRAM_SIZE, GAFFER_HEADER_SIZE};
+
+use replay::ReplayWindow;
+
+#[cfg(feature = "crypto")]
+use packet::GafferPayload;
+
+/// Message ids behind the newest one that are still recognised as duplicates by default
+pub const DEDUP_WINDOW_SIZE: u64 = 1024;
+
+/// Path MTU probing stops once the search range is this narrow, in bytes
+pub const PROBE_RESOLUTION: usize = 16;
+
+/// Datagrams in a row too far ahead of the newest sequence number that make it the new position,
+/// see `GafferConfig::max_sequence_jump`
+pub const RESYNC_PACKETS: usize = 3;
+
+/// Probes of one size that have to fail in a row before the size is taken not to get through
+pub const PROBE_ATTEMPTS: usize = 3;
 
 /// Connection to a known third party
 ///
 /// Contains:
 /// - own unacked sent-packets
 /// - ack-state of third party's packets
+/// - third party's sequence numbers already received
 /// - own dropped packets
 /// - own sequence number
+/// - own next message id, and the third party's message ids already delivered
+/// - largest datagram known to reach the third party
+/// - when the third party was last heard from
+/// - encryption keys, if any
+/// - the client id and connect token, if made with one
 #[derive(Debug)]
 pub struct Connection {
   pub seq_num: u16,
+  pub next_message_id: u16,
   pub dropped_packets: Vec<GafferPacket>,
   pub waiting_packets: AckRecord,
   pub their_acks: ExternalAcks,
+  /// Whether packets arrived that nothing sent since has carried the acks for
+  pub owes_ack: bool,
+  pub received: ReplayWindow,
+  /// Newest sequence number received too far ahead of `received`, and how many in a row were
+  pub ahead: Option<(u64, usize)>,
+  pub delivered: ReplayWindow,
+  pub path_mtu: PathMtu,
+  /// When the connection was made or last received a packet
+  pub last_activity: Instant,
+  #[cfg(feature = "crypto")]
+  pub cipher: Option<PacketCipher>,
+  /// Client id from the connect token the connection was made with
+  #[cfg(feature = "crypto")]
+  pub client_id: Option<u64>,
+  /// Private part of our connect token, resent until the server answers
+  #[cfg(feature = "crypto")]
+  pub pending_connect: Option<GafferPayload>,
 }
 
 impl Connection {
   pub fn new() -> Connection {
+    Connection::with_path_mtu(PathMtu::fixed(GAFFER_DATAGRAM_SIZE))
+  }
+
+  pub fn with_path_mtu(path_mtu: PathMtu) -> Connection {
     Connection {
       seq_num: 0,
+      next_message_id: 0,
       dropped_packets: Vec::new(),
       waiting_packets: AckRecord::new(),
-      their_acks: ExternalAcks::new()
+      their_acks: ExternalAcks::new(),
+      owes_ack: false,
+      received: ReplayWindow::new(),
+      ahead: None,
+      delivered: ReplayWindow::with_size(DEDUP_WINDOW_SIZE),
+      path_mtu,
+      last_activity: Instant::now(),
+      #[cfg(feature = "crypto")]
+      cipher: None,
+      #[cfg(feature = "crypto")]
+      client_id: None,
+      #[cfg(feature = "crypto")]
+      pending_connect: None,
     }
   }
 }
 
+/// Path MTU of a connection
+///
+/// Binary search over datagram sizes, header included. A probe of the next size is sent with a
+/// sequence number like any packet; it raises `confirmed` once acked. Probes get lost for other
+/// reasons than size, so the ceiling is only lowered once `PROBE_ATTEMPTS` probes of a size in a
+/// row are reported dropped or refused.
+#[derive(Debug)]
+pub struct PathMtu {
+  /// Largest datagram known to reach the peer
+  pub confirmed: usize,
+  /// Smallest datagram known not to, or one past the top of the search
+  ceiling: usize,
+  /// Sequence number and size of the probe waiting for an ack
+  probe: Option<(u16, usize)>,
+  /// Probes of the current size that failed in a row
+  failures: usize,
+}
+
+impl PathMtu {
+  /// A path MTU that is assumed rather than probed
+  pub fn fixed(mtu: usize) -> PathMtu {
+    PathMtu { confirmed: mtu, ceiling: mtu + 1, probe: None, failures: 0 }
+  }
+
+  /// Searches between `min`, assumed to always get through, and `max`
+  pub fn search(min: usize, max: usize) -> PathMtu {
+    PathMtu { confirmed: min, ceiling: max.max(min) + 1, probe: None, failures: 0 }
+  }
+
+  /// Whether the search has narrowed down as far as it goes
+  pub fn is_settled(&self) -> bool {
+    self.ceiling - self.confirmed <= PROBE_RESOLUTION
+  }
+
+  /// Largest payload known to reach the peer in one datagram
+  pub fn max_payload_size(&self) -> usize {
+    self.confirmed.saturating_sub(GAFFER_HEADER_SIZE)
+  }
+
+  /// Size of the next probe to send, if one is due
+  pub fn next_probe(&self) -> Option<usize> {
+    if self.probe.is_some() || self.is_settled() {
+      return None;
+    }
+    Some(self.confirmed + (self.ceiling - self.confirmed) / 2)
+  }
+
+  /// Sequence number of the probe waiting for an ack
+  pub fn probe_seq(&self) -> Option<u16> {
+    self.probe.map(|(seq, _)| seq)
+  }
+
+  /// Records a probe as sent
+  pub fn probing(&mut self, seq: u16, size: usize) {
+    self.probe = Some((seq, size));
+  }
+
+  /// The probe was acked, its size gets through
+  pub fn probe_acked(&mut self) {
+    if let Some((_, size)) = self.probe.take() {
+      self.confirmed = size;
+      self.failures = 0;
+    }
+  }
+
+  /// The probe was dropped or refused by the OS
+  ///
+  /// The same size is tried again until `PROBE_ATTEMPTS` probes in a row failed, then it is
+  /// taken not to get through.
+  pub fn probe_failed(&mut self) {
+    if let Some((_, size)) = self.probe.take() {
+      self.failures += 1;
+      if self.failures >= PROBE_ATTEMPTS {
+        self.ceiling = size;
+        self.failures = 0;
+      }
+    }
+  }
+
+  /// Forgets the probe without learning anything, so it is retried later
+  pub fn probe_cancelled(&mut self) {
+    self.probe = None;
+  }
+}
+
 /// Third party's ack information
 ///
 /// Holds the latest seq_num we've seen from them and the 32 bit bitfield 
@@ -85,14 +234,33 @@ impl AckRecord {
     AckRecord { packets: HashMap::new() }
   }
 
-  pub fn is_empty(&mut self) -> bool {
+  pub fn is_empty(&self) -> bool {
     self.packets.is_empty()
   }
 
-  pub fn len(&mut self) -> usize {
+  pub fn len(&self) -> usize {
     self.packets.len()
   }
 
+  /// Whether a packet is still waiting for an ack
+  pub fn contains(&self, seq: u16) -> bool {
+    self.packets.contains_key(&seq)
+  }
+
+  /// Stops waiting for a packet without it counting as acked or dropped
+  pub fn remove(&mut self, seq: u16) -> Option<GafferPacket> {
+    self.packets.remove(&seq)
+  }
+
+  /// Stops waiting for every packet, returning them oldest first
+  ///
+  /// `next_seq` is the sequence number the next packet will be sent with.
+  pub fn drain(&mut self, next_seq: u16) -> Vec<(u16, GafferPacket)> {
+    let mut packets: Vec<(u16, GafferPacket)> = self.packets.drain().collect();
+    packets.sort_by_key(|&(seq, _)| ::std::cmp::Reverse(next_seq.wrapping_sub(seq)));
+    packets
+  }
+
   /// Adds a packet to the waiting packets
   pub fn enqueue(&mut self, seq: u16, packet: GafferPacket) {
     // TODO: Handle overwriting other packet?
@@ -0,0 +1,62 @@
+use hmac::{Hmac, Mac};
+
+use sha2::Sha256;
+
+use std::net::{IpAddr, SocketAddr};
+
+/// Bytes of a challenge cookie: an 8 byte timestamp and a 16 byte truncated HMAC-SHA256
+pub const COOKIE_SIZE: usize = 24;
+
+/// Seconds a cookie can be echoed back in
+pub const COOKIE_LIFETIME: u64 = 10;
+
+const MAC_SIZE: usize = COOKIE_SIZE - 8;
+
+/// Issues and checks the cookies new addresses are challenged with
+///
+/// A cookie binds an address to the time it was issued, so a server can tell that a peer
+/// really receives datagrams sent to its address without remembering anything about it.
+#[derive(Clone)]
+pub struct Cookies {
+  mac: Hmac<Sha256>,
+}
+
+impl Cookies {
+  pub fn new(key: &[u8; 32]) -> Cookies {
+    Cookies { mac: Hmac::new_from_slice(key).expect("hmac takes keys of any size") }
+  }
+
+  /// The cookie for `addr` at `now`, in seconds since the unix epoch
+  pub fn bake(&self, addr: &SocketAddr, now: u64) -> [u8; COOKIE_SIZE] {
+    let mut cookie = [0; COOKIE_SIZE];
+    cookie[..8].copy_from_slice(&now.to_be_bytes());
+    let tag = self.tag(addr, now).finalize().into_bytes();
+    cookie[8..].copy_from_slice(&tag[..MAC_SIZE]);
+    cookie
+  }
+
+  /// Whether `cookie` was issued to `addr` no more than `COOKIE_LIFETIME` seconds ago
+  pub fn check(&self, addr: &SocketAddr, cookie: &[u8], now: u64) -> bool {
+    if cookie.len() != COOKIE_SIZE {
+      return false;
+    }
+    let mut issued = [0; 8];
+    issued.copy_from_slice(&cookie[..8]);
+    let issued = u64::from_be_bytes(issued);
+    if issued > now || now - issued >= COOKIE_LIFETIME {
+      return false;
+    }
+    self.tag(addr, issued).verify_truncated_left(&cookie[8..]).is_ok()
+  }
+
+  fn tag(&self, addr: &SocketAddr, issued: u64) -> Hmac<Sha256> {
+    let mut mac = self.mac.clone();
+    mac.update(&issued.to_be_bytes());
+    match addr.ip() {
+      IpAddr::V4(ip) => mac.update(&ip.octets()),
+      IpAddr::V6(ip) => mac.update(&ip.octets()),
+    }
+    mac.update(&addr.port().to_be_bytes());
+    mac
+  }
+}
@@ -0,0 +1,253 @@
+use chacha20poly1305::{ChaCha20Poly1305, Key, KeyInit, Nonce, Tag};
+use chacha20poly1305::aead::AeadInPlace;
+
+use hkdf::Hkdf;
+
+use packet::GAFFER_HEADER_SIZE;
+
+use replay::ReplayWindow;
+
+use sha2::Sha256;
+
+use std::error::Error;
+
+use std::fmt;
+
+use std::io;
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Size of the authentication tag after the payload of every encrypted datagram
+pub const GAFFER_TAG_SIZE: usize = 16; /* bytes */
+
+/// Size of the session id and the high bits of the sequence number between the header and the
+/// payload of every encrypted datagram
+pub const GAFFER_SESSION_SIZE: usize = 12; /* bytes */
+
+/// Size of the session id at the start of the session header
+const SESSION_ID_SIZE: usize = 8;
+
+/// Last session id handed out in this process, so ids started together still increase
+static LAST_SESSION: AtomicU64 = AtomicU64::new(0);
+
+/// Binds derived keys to their use, so they never match a key derived for anything else
+const SESSION_INFO: &[u8] = b"gaffer_udp session key";
+
+/// Pre-shared ChaCha20-Poly1305 keys for one connection
+///
+/// Each direction has its own key, and every `PacketCipher` derives a fresh one from it per
+/// session, so both sides can count sequence numbers from zero without ever using a nonce twice.
+/// The peer uses the same keys `reversed`.
+#[derive(Clone, PartialEq, Eq)]
+pub struct PacketKeys {
+  /// Encrypts what we send
+  pub send: [u8; 32],
+  /// Decrypts what the peer sends
+  pub recv: [u8; 32],
+}
+
+impl PacketKeys {
+  pub fn new(send: [u8; 32], recv: [u8; 32]) -> PacketKeys {
+    PacketKeys { send, recv }
+  }
+
+  /// The same keys as seen from the peer
+  pub fn reversed(&self) -> PacketKeys {
+    PacketKeys { send: self.recv, recv: self.send }
+  }
+}
+
+/// Never prints the keys themselves
+impl fmt::Debug for PacketKeys {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str("PacketKeys { .. }")
+  }
+}
+
+/// Encryption state of a connection
+///
+/// Every cipher starts a session with an id taken from the clock, sent in front of the payload,
+/// and encrypts with a key derived from the pre-shared one and that id by HKDF-SHA256. Counters
+/// restarting at zero, because keys were installed again or either side restarted, therefore
+/// never repeat a (key, nonce) pair. Nonces are the 16 bit sequence number extended to 64 bits;
+/// the bits above the header's 16 follow the session id, so a peer can open any datagram of a
+/// session without having seen the ones before it.
+///
+/// Payloads are encrypted with the header and session header as associated data, so none of
+/// them can be changed in flight. Received sequence numbers are checked against the
+/// connection's `ReplayWindow`, the only one kept. A datagram from a later session of the peer
+/// replaces the current one once it authenticates; session ids only ever increase, so every
+/// earlier session is refused from then on.
+#[derive(Clone)]
+pub struct PacketCipher {
+  session: u64,
+  send: ChaCha20Poly1305,
+  last_sent: Option<u64>,
+  recv_key: [u8; 32],
+  peer_session: Option<(u64, ChaCha20Poly1305)>,
+}
+
+impl PacketCipher {
+  /// Starts a session with an id later than any handed out before
+  ///
+  /// Ids are microseconds since the Unix epoch, bumped past the last one if the clock has not
+  /// moved on since. After a restart with the clock set back, the peer refuses the new session
+  /// until it forgets the old one. Fails only if the clock is before the epoch.
+  pub fn new(keys: &PacketKeys) -> io::Result<PacketCipher> {
+    let session = next_session_id()?;
+    Ok(PacketCipher {
+      session,
+      send: session_cipher(&keys.send, session),
+      last_sent: None,
+      recv_key: keys.recv,
+      peer_session: None,
+    })
+  }
+
+  /// Encrypts the payload of an encoded datagram in place, adding the session id and the tag
+  ///
+  /// `seq` must be the sequence number in the datagram's header. Sequence numbers only ever move
+  /// forward by one on the send side, which is what extends them without repeating a nonce.
+  pub fn seal(&mut self, seq: u16, datagram: &mut Vec<u8>) {
+    let extended = match self.last_sent {
+      Some(last) => last + seq.wrapping_sub(last as u16) as u64,
+      None => seq as u64,
+    };
+    self.last_sent = Some(extended);
+
+    let mut header = [0; GAFFER_SESSION_SIZE];
+    header[..SESSION_ID_SIZE].copy_from_slice(&self.session.to_be_bytes());
+    header[SESSION_ID_SIZE..].copy_from_slice(&((extended >> 16) as u32).to_be_bytes());
+    datagram.splice(GAFFER_HEADER_SIZE..GAFFER_HEADER_SIZE, header.iter().cloned());
+    let tag = {
+      let (associated, payload) = datagram.split_at_mut(GAFFER_HEADER_SIZE + GAFFER_SESSION_SIZE);
+      self.send.encrypt_in_place_detached(&nonce(extended), associated, payload)
+        .expect("gaffer datagrams are far below the chacha20poly1305 message limit")
+    };
+    datagram.extend_from_slice(&tag);
+  }
+
+  /// Authenticates a received datagram and decrypts its payload into `payload`
+  ///
+  /// `received` is the window of sequence numbers already seen in the current session. Ones it
+  /// holds are refused before decrypting; marking this one as seen is left to the caller once
+  /// the datagram is otherwise accepted, so forged datagrams cannot use up the window. Returns
+  /// the extended sequence number to mark. A new session starts a new count, so `received` has
+  /// to start over with it.
+  pub fn open(&mut self, seq: u16, received: &ReplayWindow, datagram: &[u8], payload: &mut Vec<u8>) -> Result<(Session, u64), Rejected> {
+    if datagram.len() < GAFFER_HEADER_SIZE + GAFFER_SESSION_SIZE + GAFFER_TAG_SIZE {
+      return Err(Rejected::Unauthenticated);
+    }
+    let (associated, rest) = datagram.split_at(GAFFER_HEADER_SIZE + GAFFER_SESSION_SIZE);
+    let (ciphertext, tag) = rest.split_at(rest.len() - GAFFER_TAG_SIZE);
+    let (session, high) = associated[GAFFER_HEADER_SIZE..].split_at(SESSION_ID_SIZE);
+    let session = u64::from_be_bytes([session[0], session[1], session[2], session[3], session[4], session[5], session[6], session[7]]);
+    let extended = (u32::from_be_bytes([high[0], high[1], high[2], high[3]]) as u64) << 16 | seq as u64;
+
+    let fresh = match self.peer_session {
+      Some((current, _)) if session == current => {
+        if !received.check(extended) {
+          return Err(Rejected::Replayed);
+        }
+        None
+      },
+      Some((current, _)) if session < current => return Err(Rejected::Replayed),
+      _ => Some(session_cipher(&self.recv_key, session)),
+    };
+    let cipher = match (&fresh, &self.peer_session) {
+      (Some(cipher), _) | (None, Some((_, cipher))) => cipher,
+      (None, None) => return Err(Rejected::Unauthenticated),
+    };
+    payload.extend_from_slice(ciphertext);
+    cipher.decrypt_in_place_detached(&nonce(extended), associated, payload, Tag::from_slice(tag))
+      .map_err(|_| Rejected::Unauthenticated)?;
+
+    let cipher = match fresh {
+      Some(cipher) => cipher,
+      None => return Ok((Session::Current, extended)),
+    };
+    match self.peer_session.replace((session, cipher)) {
+      Some(_) => Ok((Session::Replaced, extended)),
+      None => Ok((Session::First, extended)),
+    }
+  }
+}
+
+impl fmt::Debug for PacketCipher {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("PacketCipher")
+      .field("session", &self.session)
+      .field("last_sent", &self.last_sent)
+      .field("peer_session", &self.peer_session.as_ref().map(|&(id, _)| id))
+      .finish()
+  }
+}
+
+/// Which session of the peer an authenticated datagram belongs to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Session {
+  /// The one datagrams were already received from
+  Current,
+  /// The first one received from the peer
+  First,
+  /// A new one, after the peer installed its keys again or restarted; it counts message ids
+  /// from zero again too
+  Replaced,
+}
+
+/// Why a datagram was refused by the encryption layer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rejected {
+  /// Its sequence number was already seen, or is too old to tell
+  Replayed,
+  /// It failed authentication, or came from a peer without keys
+  Unauthenticated,
+}
+
+impl fmt::Display for Rejected {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      Rejected::Replayed => f.write_str("datagram was a replay"),
+      Rejected::Unauthenticated => f.write_str("datagram failed authentication"),
+    }
+  }
+}
+
+impl Error for Rejected {}
+
+impl From<Rejected> for io::Error {
+  fn from(rejected: Rejected) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, rejected)
+  }
+}
+
+fn nonce(extended: u64) -> Nonce {
+  let mut nonce = [0; 12];
+  nonce[4..].copy_from_slice(&extended.to_be_bytes());
+  *Nonce::from_slice(&nonce)
+}
+
+/// A session id later than the last one, see `PacketCipher::new`
+fn next_session_id() -> io::Result<u64> {
+  let now = SystemTime::now().duration_since(UNIX_EPOCH)
+    .map_err(|_| io::Error::other("clock is before the unix epoch"))?;
+  let now = now.as_secs() * 1_000_000 + now.subsec_micros() as u64;
+  let mut last = LAST_SESSION.load(Ordering::Relaxed);
+  loop {
+    let next = now.max(last + 1);
+    match LAST_SESSION.compare_exchange_weak(last, next, Ordering::Relaxed, Ordering::Relaxed) {
+      Ok(_) => return Ok(next),
+      Err(actual) => last = actual,
+    }
+  }
+}
+
+/// The cipher for one session, keyed from the pre-shared `key` with the session id as salt
+fn session_cipher(key: &[u8; 32], session: u64) -> ChaCha20Poly1305 {
+  let mut derived = [0; 32];
+  Hkdf::<Sha256>::new(Some(&session.to_be_bytes()), key).expand(SESSION_INFO, &mut derived)
+    .expect("32 bytes is a valid hkdf-sha256 output length");
+  ChaCha20Poly1305::new(Key::from_slice(&derived))
+}
@@ -0,0 +1,35 @@
+use std::io;
+
+use std::net::SocketAddr;
+
+use packet::{DisconnectReason, GafferPacket};
+
+/// Something that happened on a socket which the app may want to react to
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum GafferEvent {
+  /// A packet arrived from a peer
+  Packet(GafferPacket),
+  /// Sending to a peer failed in the background
+  SendFailed(SocketAddr, io::ErrorKind),
+  /// A peer was let in with a connect token, carrying the client id from the token
+  ///
+  /// Raised on the server once it accepts the token, and on the client once the server first
+  /// answers.
+  Connected(SocketAddr, u64),
+  /// A peer went over its rate limit, see `GafferConfig::report_abuse`
+  ///
+  /// Raised again only once the peer has been back under the limit.
+  PeerAbusive(SocketAddr),
+  /// A new peer was turned away because the socket has `GafferConfig::max_connections` already
+  PeerRejected(SocketAddr),
+  /// A connection was forgotten to make room for a new peer, see `FullPolicy::EvictLeastRecent`
+  PeerEvicted(SocketAddr),
+  /// A server we were starting to talk to turned us away for being full
+  ///
+  /// The connection to it is forgotten.
+  ServerFull(SocketAddr),
+  /// A peer said it is leaving, its connection is forgotten
+  ///
+  /// Spoofable for peers without keys, see `PacketKind::Disconnect`.
+  Disconnected(SocketAddr, DisconnectReason),
+}
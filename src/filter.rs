@@ -0,0 +1,164 @@
+use std::fmt;
+
+use std::io;
+
+use std::net::IpAddr;
+
+use std::str::FromStr;
+
+use std::time::{Duration, Instant};
+
+/// A block of addresses in CIDR notation, a single address is a /32 or /128
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct IpRange {
+  addr: IpAddr,
+  prefix: u8,
+}
+
+impl IpRange {
+  /// The range of `addr` with the top `prefix` bits fixed, the rest of `addr` is ignored
+  ///
+  /// IPv4-mapped IPv6 ranges such as `::ffff:10.0.0.0/104` are stored as the IPv4 range they
+  /// cover, like mapped addresses are matched as IPv4 ones. A mapped address with a prefix
+  /// shorter than 96 covers more than IPv4 and stays an IPv6 range.
+  pub fn new(addr: IpAddr, prefix: u8) -> io::Result<IpRange> {
+    let (addr, prefix) = match addr {
+      IpAddr::V6(_) if prefix < 96 => (addr, prefix),
+      IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+        Some(v4) if prefix <= 128 => (IpAddr::V4(v4), prefix - 96),
+        _ => (addr, prefix),
+      },
+      IpAddr::V4(_) => (addr, prefix),
+    };
+    let bits = match addr {
+      IpAddr::V4(_) => 32,
+      IpAddr::V6(_) => 128,
+    };
+    if prefix > bits {
+      return Err(io::Error::new(io::ErrorKind::InvalidInput, "prefix is longer than the address"));
+    }
+    Ok(IpRange { addr: mask(addr, prefix), prefix })
+  }
+
+  pub fn contains(&self, addr: &IpAddr) -> bool {
+    let addr = addr.to_canonical();
+    match (self.addr, addr) {
+      (IpAddr::V4(_), IpAddr::V4(_)) | (IpAddr::V6(_), IpAddr::V6(_)) => mask(addr, self.prefix) == self.addr,
+      _ => false,
+    }
+  }
+}
+
+impl From<IpAddr> for IpRange {
+  fn from(addr: IpAddr) -> IpRange {
+    let addr = addr.to_canonical();
+    let prefix = if addr.is_ipv4() { 32 } else { 128 };
+    IpRange { addr, prefix }
+  }
+}
+
+/// Parses `10.0.0.0/8`, `2001:db8::/32` or a bare address
+impl FromStr for IpRange {
+  type Err = io::Error;
+
+  fn from_str(s: &str) -> io::Result<IpRange> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "not an address or CIDR range");
+    let mut parts = s.splitn(2, '/');
+    let addr = parts.next().unwrap_or("").parse::<IpAddr>().map_err(|_| invalid())?;
+    match parts.next() {
+      Some(prefix) => IpRange::new(addr, prefix.parse().map_err(|_| invalid())?),
+      None => Ok(IpRange::from(addr)),
+    }
+  }
+}
+
+impl fmt::Display for IpRange {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}/{}", self.addr, self.prefix)
+  }
+}
+
+fn mask(addr: IpAddr, prefix: u8) -> IpAddr {
+  match addr {
+    IpAddr::V4(ip) => {
+      let bits = u32::from(ip).checked_shr(32 - prefix as u32).unwrap_or(0).checked_shl(32 - prefix as u32).unwrap_or(0);
+      IpAddr::V4(bits.into())
+    },
+    IpAddr::V6(ip) => {
+      let bits = u128::from(ip).checked_shr(128 - prefix as u32).unwrap_or(0).checked_shl(128 - prefix as u32).unwrap_or(0);
+      IpAddr::V6(bits.into())
+    },
+  }
+}
+
+/// Decides which addresses a socket listens to
+///
+/// Bans and the deny list always win. When the allow list has any entries, only addresses in it
+/// get through; an empty allow list lets everyone through. Bans lift themselves once they
+/// expire.
+#[derive(Clone, Debug, Default)]
+pub struct AddressFilter {
+  allow: Vec<IpRange>,
+  deny: Vec<IpRange>,
+  bans: Vec<(IpRange, Instant)>,
+}
+
+impl AddressFilter {
+  pub fn new() -> AddressFilter {
+    AddressFilter::default()
+  }
+
+  pub fn allow(&mut self, range: IpRange) {
+    if !self.allow.contains(&range) {
+      self.allow.push(range);
+    }
+  }
+
+  pub fn deny(&mut self, range: IpRange) {
+    if !self.deny.contains(&range) {
+      self.deny.push(range);
+    }
+  }
+
+  /// Takes a range off both the allow and the deny list
+  pub fn remove(&mut self, range: &IpRange) {
+    self.allow.retain(|allowed| allowed != range);
+    self.deny.retain(|denied| denied != range);
+  }
+
+  /// Shuts a range out for `duration`, extending any ban it already has
+  pub fn ban(&mut self, range: IpRange, duration: Duration) {
+    let until = Instant::now() + duration;
+    match self.bans.iter_mut().find(|&&mut (banned, _)| banned == range) {
+      Some(ban) => ban.1 = ban.1.max(until),
+      None => self.bans.push((range, until)),
+    }
+  }
+
+  /// Lifts a ban early, returning whether there was one
+  pub fn unban(&mut self, range: &IpRange) -> bool {
+    let before = self.bans.len();
+    self.bans.retain(|&(banned, _)| banned != *range);
+    self.bans.len() != before
+  }
+
+  /// Ranges still banned at `now`, with when each ban ends
+  pub fn bans(&self, now: Instant) -> Vec<(IpRange, Instant)> {
+    self.bans.iter().filter(|&&(_, until)| until > now).cloned().collect()
+  }
+
+  /// Whether datagrams from `addr` get through at `now`, forgetting bans that have expired
+  pub fn permits(&mut self, addr: &IpAddr, now: Instant) -> bool {
+    if self.allow.is_empty() && self.deny.is_empty() && self.bans.is_empty() {
+      return true;
+    }
+    self.bans.retain(|&(_, until)| until > now);
+    if self.bans.iter().any(|&(banned, _)| banned.contains(addr)) {
+      return false;
+    }
+    if self.deny.iter().any(|denied| denied.contains(addr)) {
+      return false;
+    }
+    self.allow.is_empty() || self.allow.iter().any(|allowed| allowed.contains(addr))
+  }
+}
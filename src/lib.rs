@@ -1,23 +1,60 @@
 extern crate byteorder;
 extern crate itertools;
 extern crate mio;
+extern crate socket2;
+#[cfg(target_os = "linux")]
+extern crate libc;
+#[cfg(feature = "async")]
+extern crate tokio;
+#[cfg(feature = "crypto")]
+extern crate chacha20poly1305;
+#[cfg(feature = "crypto")]
+extern crate hkdf;
+#[cfg(feature = "crypto")]
+extern crate hmac;
+#[cfg(feature = "crypto")]
+extern crate sha2;
 
 pub mod addr;
+pub mod config;
 pub mod packet;
+pub mod pool;
+pub mod replay;
 pub mod connection;
+#[cfg(feature = "crypto")]
+pub mod cookie;
+#[cfg(feature = "crypto")]
+pub mod crypto;
+pub mod event;
+pub mod filter;
+pub mod limit;
 pub mod socket;
+pub mod stats;
+#[cfg(feature = "crypto")]
+pub mod token;
 
 pub use addr::ToSingleSocketAddr;
+pub use config::{FullPolicy, GafferConfig};
 pub use packet::*;
+pub use pool::BufferPool;
+pub use replay::ReplayWindow;
 pub use connection::*;
+#[cfg(feature = "crypto")]
+pub use crypto::{PacketKeys, GAFFER_SESSION_SIZE, GAFFER_TAG_SIZE};
+pub use event::*;
+pub use filter::{AddressFilter, IpRange};
+pub use limit::RateLimit;
 pub use socket::*;
+pub use stats::GafferStats;
+#[cfg(feature = "crypto")]
+pub use token::{ConnectToken, TokenGenerator};
 
 #[cfg(test)]
 mod test {
-  pub use addr::ToSingleSocketAddr;
   pub use packet::*;
+  pub use pool::*;
+  pub use replay::*;
   pub use connection::*;
-  pub use socket::*;
 
   mod complete_gaffer_packet {
     use super::*;
@@ -25,15 +62,620 @@ mod test {
     #[test]
     fn it_serializes() {
       let packet = CompleteGafferPacket {
+        kind: PacketKind::Data,
         seq: 6,
         ack_seq: 20,
         ack_field: 1,
-        payload: vec![1,2,3,4]
+        message_id: 9,
+        payload: vec![1,2,3,4].into()
       };
       let bytes = packet.clone().serialized();
       let new_packet = CompleteGafferPacket::deserialize(bytes).unwrap();
       assert_eq!(packet, new_packet);
     }
+
+    #[test]
+    fn it_rejects_short_datagrams() {
+      assert!(CompleteGafferPacket::deserialize(vec![1, 2, 3]).is_err());
+    }
+
+    #[test]
+    fn it_rejects_unknown_kinds() {
+      assert!(CompleteGafferPacket::deserialize(vec![GAFFER_VERSION, 255, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn it_rejects_other_versions() {
+      let mut bytes = CompleteGafferPacket {
+        kind: PacketKind::Data,
+        seq: 0,
+        ack_seq: 0,
+        ack_field: 0,
+        message_id: 0,
+        payload: vec![1].into()
+      }.serialized();
+      bytes[0] = GAFFER_VERSION + 1;
+      assert!(CompleteGafferPacket::deserialize(bytes).is_err());
+    }
+
+    #[test]
+    fn disconnect_reasons_round_trip() {
+      let reasons = [
+        DisconnectReason::UserQuit,
+        DisconnectReason::Kicked,
+        DisconnectReason::Timeout,
+        DisconnectReason::ProtocolMismatch,
+        DisconnectReason::Custom(42),
+        DisconnectReason::Shutdown,
+      ];
+      for &reason in reasons.iter() {
+        assert_eq!(DisconnectReason::from_bytes(&reason.to_bytes()), Some(reason));
+      }
+      assert_eq!(DisconnectReason::from_bytes(&[]), None);
+      assert_eq!(DisconnectReason::from_bytes(&[9]), None);
+      assert_eq!(DisconnectReason::from_bytes(&[0, 1]), None);
+    }
+  }
+
+  mod gaffer_payload {
+    use super::*;
+
+    #[test]
+    fn clones_share_bytes() {
+      let payload = GafferPayload::from(vec![1, 2, 3]);
+      let packets: Vec<GafferPacket> = (0..3).map(|port| GafferPacket::new(("127.0.0.1", port), payload.clone())).collect();
+      assert!(packets.iter().all(|p| p.payload.as_ptr() == payload.as_ptr()));
+      assert_eq!(packets[0].payload, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn into_vec_copies_only_when_shared() {
+      let bytes = vec![1, 2, 3];
+      let ptr = bytes.as_ptr();
+      let unshared = GafferPayload::from(bytes).into_vec();
+      assert_eq!(unshared.as_ptr(), ptr);
+
+      let payload = GafferPayload::from(vec![1, 2, 3]);
+      let shared = payload.clone();
+      let copied = payload.into_vec();
+      assert_ne!(copied.as_ptr(), shared.as_ptr());
+    }
+  }
+
+  mod buffer_pool {
+    use super::*;
+    use itertools::Itertools;
+
+    #[test]
+    fn reuses_buffers() {
+      let pool = BufferPool::new(4, 64);
+      let mut buffer = pool.take();
+      assert!(buffer.capacity() >= 64);
+      buffer.extend_from_slice(&[1, 2, 3]);
+      let ptr = buffer.as_ptr();
+      pool.give(buffer);
+
+      let reused = pool.take();
+      assert_eq!(reused.as_ptr(), ptr);
+      assert!(reused.is_empty());
+    }
+
+    #[test]
+    fn keeps_at_most_capacity() {
+      let pool = BufferPool::new(2, 64);
+      let buffers: Vec<Vec<u8>> = (0..3).map(|_| pool.take()).collect();
+      buffers.into_iter().foreach(|buffer| pool.give(buffer));
+      assert_eq!(pool.idle(), 2);
+    }
+
+    #[test]
+    fn frees_undersized_buffers() {
+      let pool = BufferPool::new(4, 64);
+      pool.give(Vec::with_capacity(16));
+      assert_eq!(pool.idle(), 0);
+    }
+
+    #[test]
+    fn payloads_return_on_last_drop() {
+      let pool = BufferPool::new(4, 64);
+      let payload = GafferPayload::pooled(pool.take(), &pool);
+      let shared = payload.clone();
+      drop(payload);
+      assert_eq!(pool.idle(), 0);
+      drop(shared);
+      assert_eq!(pool.idle(), 1);
+    }
+
+    #[test]
+    fn decodes_into_pooled_buffers() {
+      let pool = BufferPool::new(4, 64);
+      let packet = CompleteGafferPacket { kind: PacketKind::Data, seq: 1, ack_seq: 0, ack_field: 0, message_id: 0, payload: vec![7; 10].into() };
+      let mut datagram = pool.take();
+      packet.serialize_into(&mut datagram);
+
+      let decoded = CompleteGafferPacket::deserialize_pooled(&datagram, &pool).unwrap();
+      assert_eq!(decoded, packet);
+      pool.give(datagram);
+      drop(decoded);
+      assert_eq!(pool.idle(), 2);
+    }
+  }
+
+  mod replay_window {
+    use super::*;
+
+    #[test]
+    fn rejects_duplicates() {
+      let mut window = ReplayWindow::new();
+      assert!(window.check(5));
+      window.accept(5);
+      assert!(!window.check(5));
+      assert!(window.check(4));
+      assert!(window.check(6));
+    }
+
+    #[test]
+    fn rejects_packets_older_than_the_window() {
+      let mut window = ReplayWindow::new();
+      window.accept(100);
+      assert!(window.check(100 - REPLAY_WINDOW_SIZE + 1));
+      assert!(!window.check(100 - REPLAY_WINDOW_SIZE));
+    }
+
+    #[test]
+    fn only_contains_what_it_still_tracks() {
+      let mut window = ReplayWindow::new();
+      window.accept(10);
+      window.accept(100);
+      assert!(window.contains(100));
+      assert!(!window.contains(99));
+      assert!(!window.contains(101));
+      // Seen, but out of the window by now
+      assert!(!window.contains(10));
+    }
+
+    #[test]
+    fn remembers_across_jumps() {
+      let mut window = ReplayWindow::new();
+      window.accept(10);
+      window.accept(12);
+      window.accept(11);
+      assert!(!window.check(10));
+      assert!(!window.check(11));
+      assert!(!window.check(12));
+      window.accept(12 + REPLAY_WINDOW_SIZE);
+      assert!(!window.check(12));
+    }
+
+    #[test]
+    fn wide_windows_reuse_slots() {
+      let mut window = ReplayWindow::with_size(100);
+      assert_eq!(window.size(), 128);
+      window.accept(0);
+      window.accept(5);
+      assert!(!window.check(5));
+      assert!(window.check(3));
+      // Lands on the same slot as 5, which has to read as unseen again
+      window.accept(130);
+      assert!(window.check(5 + 128));
+      assert!(!window.check(5));
+      assert!(window.check(130 - 127));
+      assert!(!window.check(130 - 128));
+    }
+
+    #[test]
+    fn extends_across_wraps() {
+      let mut window = ReplayWindow::new();
+      window.accept(65530);
+      assert_eq!(window.extend(65535), 65535);
+      assert_eq!(window.extend(3), 65539);
+      window.accept(65539);
+      assert_eq!(window.extend(65534), 65534);
+      assert_eq!(window.extend(4), 65540);
+    }
+  }
+
+  #[cfg(feature = "crypto")]
+  mod packet_cipher {
+    use super::*;
+    use crypto::{PacketCipher, PacketKeys, Rejected, Session, GAFFER_SESSION_SIZE, GAFFER_TAG_SIZE};
+
+    fn keys() -> PacketKeys {
+      PacketKeys::new([1; 32], [2; 32])
+    }
+
+    fn pair() -> (PacketCipher, PacketCipher) {
+      (PacketCipher::new(&keys()).unwrap(), PacketCipher::new(&keys().reversed()).unwrap())
+    }
+
+    fn sealed(cipher: &mut PacketCipher, seq: u16, payload: Vec<u8>) -> Vec<u8> {
+      let packet = CompleteGafferPacket { kind: PacketKind::Data, seq, ack_seq: 0, ack_field: 0, message_id: 0, payload: payload.into() };
+      let mut datagram = packet.serialized();
+      cipher.seal(seq, &mut datagram);
+      datagram
+    }
+
+    /// Opens `datagram` the way a socket does, keeping the peer's window in `received`
+    fn open(cipher: &mut PacketCipher, received: &mut ReplayWindow, seq: u16, datagram: &[u8], payload: &mut Vec<u8>) -> Result<Session, Rejected> {
+      let (session, extended) = cipher.open(seq, received, datagram, payload)?;
+      if session != Session::Current {
+        *received = ReplayWindow::new();
+      }
+      received.accept(extended);
+      Ok(session)
+    }
+
+    #[test]
+    fn round_trips() {
+      let (mut ours, mut theirs) = pair();
+      let mut window = ReplayWindow::new();
+      let datagram = sealed(&mut ours, 0, vec![1, 2, 3]);
+      assert_eq!(datagram.len(), GAFFER_HEADER_SIZE + GAFFER_SESSION_SIZE + 3 + GAFFER_TAG_SIZE);
+      assert!(!datagram.ends_with(&[1, 2, 3]));
+
+      let mut payload = Vec::new();
+      assert_eq!(open(&mut theirs, &mut window, 0, &datagram, &mut payload), Ok(Session::First));
+      assert_eq!(payload, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn never_repeats_nonces_across_sessions() {
+      let (mut ours, mut theirs) = pair();
+      let mut window = ReplayWindow::new();
+      let first = sealed(&mut ours, 0, vec![1]);
+      open(&mut theirs, &mut window, 0, &first, &mut Vec::new()).unwrap();
+
+      // Keys installed again, or a restart, counts from zero under a new session key
+      let mut restarted = PacketCipher::new(&keys()).unwrap();
+      let second = sealed(&mut restarted, 0, vec![1]);
+      assert_ne!(first[GAFFER_HEADER_SIZE..], second[GAFFER_HEADER_SIZE..]);
+
+      let mut payload = Vec::new();
+      assert_eq!(open(&mut theirs, &mut window, 0, &second, &mut payload), Ok(Session::Replaced));
+      assert_eq!(payload, vec![1]);
+      assert_eq!(open(&mut theirs, &mut window, 1, &sealed(&mut restarted, 1, vec![2]), &mut Vec::new()), Ok(Session::Current));
+    }
+
+    #[test]
+    fn refuses_replaced_sessions() {
+      let (mut ours, mut theirs) = pair();
+      let mut window = ReplayWindow::new();
+      let old = sealed(&mut ours, 0, vec![1]);
+      let later = sealed(&mut ours, 1, vec![1]);
+      open(&mut theirs, &mut window, 0, &old, &mut Vec::new()).unwrap();
+
+      let mut restarted = PacketCipher::new(&keys()).unwrap();
+      open(&mut theirs, &mut window, 0, &sealed(&mut restarted, 0, vec![1]), &mut Vec::new()).unwrap();
+      assert_eq!(open(&mut theirs, &mut window, 1, &later, &mut Vec::new()), Err(Rejected::Replayed));
+    }
+
+    #[test]
+    fn refuses_every_earlier_session() {
+      let (mut ours, mut theirs) = pair();
+      let mut window = ReplayWindow::new();
+      let first = sealed(&mut ours, 0, vec![1]);
+      open(&mut theirs, &mut window, 0, &first, &mut Vec::new()).unwrap();
+      let mut older = PacketCipher::new(&keys()).unwrap();
+      for _ in 0..32 {
+        let mut restarted = PacketCipher::new(&keys()).unwrap();
+        open(&mut theirs, &mut window, 0, &sealed(&mut restarted, 0, vec![1]), &mut Vec::new()).unwrap();
+      }
+      assert_eq!(open(&mut theirs, &mut window, 0, &first, &mut Vec::new()), Err(Rejected::Replayed));
+      // Started before the current one, even though it was never seen
+      assert_eq!(open(&mut theirs, &mut window, 0, &sealed(&mut older, 0, vec![1]), &mut Vec::new()), Err(Rejected::Replayed));
+    }
+
+    #[test]
+    fn joins_sessions_past_the_first_wrap() {
+      let (mut ours, _) = pair();
+      for seq in 0..=u16::MAX {
+        sealed(&mut ours, seq, vec![]);
+      }
+      // Keys installed again on the receiving side only, while the peer keeps its session
+      let mut theirs = PacketCipher::new(&keys().reversed()).unwrap();
+      let mut window = ReplayWindow::new();
+      let mut payload = Vec::new();
+      assert_eq!(open(&mut theirs, &mut window, 4, &sealed(&mut ours, 4, vec![1]), &mut payload), Ok(Session::First));
+      assert_eq!(payload, vec![1]);
+      assert_eq!(open(&mut theirs, &mut window, 5, &sealed(&mut ours, 5, vec![2]), &mut Vec::new()), Ok(Session::Current));
+    }
+
+    #[test]
+    fn rejects_forged_sessions() {
+      let (mut ours, mut theirs) = pair();
+      let mut window = ReplayWindow::new();
+      open(&mut theirs, &mut window, 0, &sealed(&mut ours, 0, vec![1]), &mut Vec::new()).unwrap();
+      let mut forged = sealed(&mut ours, 1, vec![1]);
+      forged[GAFFER_HEADER_SIZE] ^= 1;
+      assert_eq!(open(&mut theirs, &mut window, 1, &forged, &mut Vec::new()), Err(Rejected::Unauthenticated));
+      // The real session is still the current one
+      assert_eq!(open(&mut theirs, &mut window, 2, &sealed(&mut ours, 2, vec![1]), &mut Vec::new()), Ok(Session::Current));
+    }
+
+    #[test]
+    fn rejects_tampered_headers() {
+      let (mut ours, mut theirs) = pair();
+      let mut window = ReplayWindow::new();
+      let mut datagram = sealed(&mut ours, 0, vec![1, 2, 3]);
+      // A spoofed ack
+      datagram[4] ^= 1;
+      assert_eq!(open(&mut theirs, &mut window, 0, &datagram, &mut Vec::new()), Err(Rejected::Unauthenticated));
+    }
+
+    #[test]
+    fn rejects_the_wrong_direction() {
+      let (mut ours, _) = pair();
+      let mut window = ReplayWindow::new();
+      let datagram = sealed(&mut ours, 0, vec![1]);
+      assert_eq!(open(&mut ours, &mut window, 0, &datagram, &mut Vec::new()), Err(Rejected::Unauthenticated));
+    }
+
+    #[test]
+    fn rejects_replays() {
+      let (mut ours, mut theirs) = pair();
+      let mut window = ReplayWindow::new();
+      let datagram = sealed(&mut ours, 0, vec![1]);
+      open(&mut theirs, &mut window, 0, &datagram, &mut Vec::new()).unwrap();
+      assert_eq!(open(&mut theirs, &mut window, 0, &datagram, &mut Vec::new()), Err(Rejected::Replayed));
+    }
+
+    #[test]
+    fn keeps_nonces_unique_across_wraps() {
+      let (mut ours, mut theirs) = pair();
+      let mut window = ReplayWindow::new();
+      let first = sealed(&mut ours, 0, vec![1]);
+      open(&mut theirs, &mut window, 0, &first, &mut Vec::new()).unwrap();
+      for seq in 1..=u16::MAX {
+        let datagram = sealed(&mut ours, seq, vec![]);
+        if seq % 0x4000 == 0 || seq == u16::MAX {
+          open(&mut theirs, &mut window, seq, &datagram, &mut Vec::new()).unwrap();
+        }
+      }
+
+      // Same sequence number and payload as the first datagram, but a different nonce
+      let wrapped = sealed(&mut ours, 0, vec![1]);
+      assert_ne!(first, wrapped);
+      let mut payload = Vec::new();
+      open(&mut theirs, &mut window, 0, &wrapped, &mut payload).unwrap();
+      assert_eq!(payload, vec![1]);
+      assert_eq!(open(&mut theirs, &mut window, 0, &first, &mut Vec::new()), Err(Rejected::Replayed));
+    }
+  }
+
+  #[cfg(feature = "crypto")]
+  mod cookies {
+    use cookie::{Cookies, COOKIE_LIFETIME};
+    use std::net::SocketAddr;
+
+    fn addr() -> SocketAddr {
+      "127.0.0.1:40000".parse().unwrap()
+    }
+
+    #[test]
+    fn accepts_fresh_cookies() {
+      let cookies = Cookies::new(&[5; 32]);
+      let cookie = cookies.bake(&addr(), 1000);
+      assert!(cookies.check(&addr(), &cookie, 1000));
+      assert!(cookies.check(&addr(), &cookie, 1000 + COOKIE_LIFETIME - 1));
+    }
+
+    #[test]
+    fn rejects_stale_and_misdirected_cookies() {
+      let cookies = Cookies::new(&[5; 32]);
+      let cookie = cookies.bake(&addr(), 1000);
+      assert!(!cookies.check(&addr(), &cookie, 1000 + COOKIE_LIFETIME));
+      assert!(!cookies.check(&addr(), &cookie, 999));
+      assert!(!cookies.check(&"127.0.0.1:40001".parse().unwrap(), &cookie, 1000));
+      assert!(!Cookies::new(&[6; 32]).check(&addr(), &cookie, 1000));
+      assert!(!cookies.check(&addr(), &cookie[..20], 1000));
+    }
+
+    #[test]
+    fn rejects_moved_timestamps() {
+      let cookies = Cookies::new(&[5; 32]);
+      let mut cookie = cookies.bake(&addr(), 1000);
+      cookie[7] = 0xff;
+      assert!(!cookies.check(&addr(), &cookie, 1000 + 0xff));
+    }
+  }
+
+  #[cfg(feature = "crypto")]
+  mod connect_token {
+    use crypto::PacketKeys;
+    use std::net::SocketAddr;
+    use std::time::{Duration, SystemTime};
+    use token::{open_token, TokenGenerator};
+
+    const KEY: [u8; 32] = [7; 32];
+
+    fn server() -> SocketAddr {
+      "127.0.0.1:40000".parse().unwrap()
+    }
+
+    #[test]
+    fn round_trips() {
+      let keys = PacketKeys::new([1; 32], [2; 32]);
+      let token = TokenGenerator::new(&KEY).generate(42, &[server()], Duration::from_secs(30), keys.clone()).unwrap();
+      let private = open_token(&KEY, &token.private, SystemTime::now()).unwrap();
+      assert_eq!(private.client_id, 42);
+      assert_eq!(private.expires_at, token.expires_at);
+      assert_eq!(private.server_addrs, vec![server()]);
+      assert_eq!(private.keys, keys.reversed());
+      assert!(private.names_server(&server()));
+      assert!(private.names_server(&"0.0.0.0:40000".parse().unwrap()));
+      assert!(!private.names_server(&"127.0.0.1:40001".parse().unwrap()));
+    }
+
+    #[test]
+    fn rejects_tampering_and_other_keys() {
+      let keys = PacketKeys::new([1; 32], [2; 32]);
+      let token = TokenGenerator::new(&KEY).generate(42, &[server()], Duration::from_secs(30), keys).unwrap();
+      assert!(open_token(&[8; 32], &token.private, SystemTime::now()).is_err());
+      let mut tampered = token.private.clone();
+      tampered[20] ^= 1;
+      assert!(open_token(&KEY, &tampered, SystemTime::now()).is_err());
+      assert!(open_token(&KEY, &token.private[..20], SystemTime::now()).is_err());
+    }
+
+    #[test]
+    fn rejects_expired_tokens() {
+      let keys = PacketKeys::new([1; 32], [2; 32]);
+      let token = TokenGenerator::new(&KEY).generate(42, &[server()], Duration::from_secs(30), keys).unwrap();
+      let later = SystemTime::now() + Duration::from_secs(31);
+      assert!(open_token(&KEY, &token.private, later).is_err());
+    }
+
+    #[test]
+    fn uses_unique_nonces() {
+      let keys = PacketKeys::new([1; 32], [2; 32]);
+      let mut generator = TokenGenerator::new(&KEY);
+      let first = generator.generate(1, &[server()], Duration::from_secs(30), keys.clone()).unwrap();
+      let second = generator.generate(1, &[server()], Duration::from_secs(30), keys).unwrap();
+      assert_ne!(first.private[..12], second.private[..12]);
+      assert!(generator.generate(1, &[], Duration::from_secs(30), PacketKeys::new([0; 32], [0; 32])).is_err());
+    }
+  }
+
+  mod address_filter {
+    use filter::{AddressFilter, IpRange};
+    use std::net::IpAddr;
+    use std::time::{Duration, Instant};
+
+    fn ip(s: &str) -> IpAddr {
+      s.parse().unwrap()
+    }
+
+    fn range(s: &str) -> IpRange {
+      s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_ranges() {
+      assert_eq!(range("10.1.2.3/8"), IpRange::new(ip("10.0.0.0"), 8).unwrap());
+      assert_eq!(range("10.1.2.3").to_string(), "10.1.2.3/32");
+      assert_eq!(range("2001:db8::1/32").to_string(), "2001:db8::/32");
+      assert!("10.0.0.0/33".parse::<IpRange>().is_err());
+      assert!("10.0.0/8".parse::<IpRange>().is_err());
+      assert_eq!(range("::ffff:10.1.2.3/104"), range("10.0.0.0/8"));
+      assert!("::ffff:10.0.0.0/129".parse::<IpRange>().is_err());
+    }
+
+    #[test]
+    fn matches_ranges() {
+      let private = range("192.168.0.0/16");
+      assert!(private.contains(&ip("192.168.4.2")));
+      assert!(!private.contains(&ip("192.169.0.1")));
+      assert!(private.contains(&ip("::ffff:192.168.4.2")));
+      assert!(!private.contains(&ip("2001:db8::1")));
+      assert!(range("0.0.0.0/0").contains(&ip("8.8.8.8")));
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+      let mut filter = AddressFilter::new();
+      let now = Instant::now();
+      assert!(filter.permits(&ip("8.8.8.8"), now));
+
+      filter.allow(range("10.0.0.0/8"));
+      filter.deny(range("10.0.0.13"));
+      assert!(filter.permits(&ip("10.0.0.12"), now));
+      assert!(!filter.permits(&ip("10.0.0.13"), now));
+      assert!(!filter.permits(&ip("8.8.8.8"), now));
+
+      filter.remove(&range("10.0.0.0/8"));
+      assert!(filter.permits(&ip("8.8.8.8"), now));
+    }
+
+    #[test]
+    fn bans_expire() {
+      let mut filter = AddressFilter::new();
+      filter.ban(range("10.0.0.0/24"), Duration::from_secs(60));
+      let now = Instant::now();
+      assert!(!filter.permits(&ip("10.0.0.7"), now));
+      assert!(filter.permits(&ip("10.0.1.7"), now));
+      assert_eq!(filter.bans(now).len(), 1);
+
+      let later = now + Duration::from_secs(61);
+      assert!(filter.permits(&ip("10.0.0.7"), later));
+      assert_eq!(filter.bans(later).len(), 0);
+
+      filter.ban(range("10.0.0.7"), Duration::from_secs(60));
+      assert!(filter.unban(&range("10.0.0.7")));
+      assert!(filter.permits(&ip("10.0.0.7"), now));
+    }
+  }
+
+  mod rate_limiter {
+    use limit::{Limited, RateLimit, RateLimiter};
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+
+    fn addr(port: u16) -> SocketAddr {
+      SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn limits_packets_per_peer() {
+      let mut limiter = RateLimiter::new(Some(RateLimit::new(2, 1000)), None, 16);
+      let now = Instant::now();
+      assert_eq!(limiter.admit(addr(1), 10, now), Ok(()));
+      assert_eq!(limiter.admit(addr(1), 10, now), Ok(()));
+      assert_eq!(limiter.admit(addr(1), 10, now), Err(Limited::Peer { first: true }));
+      assert_eq!(limiter.admit(addr(1), 10, now), Err(Limited::Peer { first: false }));
+      assert_eq!(limiter.admit(addr(2), 10, now), Ok(()));
+
+      let later = now + Duration::from_millis(500);
+      assert_eq!(limiter.admit(addr(1), 10, later), Ok(()));
+      assert_eq!(limiter.admit(addr(1), 10, later), Err(Limited::Peer { first: true }));
+    }
+
+    #[test]
+    fn limits_bytes_per_peer() {
+      let mut limiter = RateLimiter::new(Some(RateLimit::new(100, 1000)), None, 16);
+      let now = Instant::now();
+      assert_eq!(limiter.admit(addr(1), 900, now), Ok(()));
+      assert_eq!(limiter.admit(addr(1), 200, now), Err(Limited::Peer { first: true }));
+      assert_eq!(limiter.admit(addr(1), 100, now), Ok(()));
+    }
+
+    #[test]
+    fn limits_all_peers_together() {
+      let mut limiter = RateLimiter::new(Some(RateLimit::new(2, 1000)), Some(RateLimit::new(3, 1000)), 16);
+      let now = Instant::now();
+      assert_eq!(limiter.admit(addr(1), 10, now), Ok(()));
+      assert_eq!(limiter.admit(addr(2), 10, now), Ok(()));
+      assert_eq!(limiter.admit(addr(3), 10, now), Ok(()));
+      assert_eq!(limiter.admit(addr(4), 10, now), Err(Limited::Global));
+      // A refused datagram takes nothing from the peer's own buckets
+      assert_eq!(limiter.admit(addr(4), 10, now + Duration::from_millis(334)), Ok(()));
+    }
+
+    #[test]
+    fn caps_tracked_peers() {
+      let mut limiter = RateLimiter::new(Some(RateLimit::new(2, 1000)), Some(RateLimit::new(3, 1000)), 1);
+      let now = Instant::now();
+      assert_eq!(limiter.admit(addr(1), 10, now), Ok(()));
+      // Only the global buckets are left for the next address
+      assert_eq!(limiter.admit(addr(2), 10, now), Ok(()));
+      assert_eq!(limiter.admit(addr(2), 10, now), Ok(()));
+      assert_eq!(limiter.admit(addr(2), 10, now), Err(Limited::Global));
+      assert_eq!(limiter.tracked(), 1);
+
+      let mut limiter = RateLimiter::new(Some(RateLimit::new(2, 1000)), None, 1);
+      assert_eq!(limiter.admit(addr(1), 10, now), Ok(()));
+      assert_eq!(limiter.admit(addr(2), 10, now), Err(Limited::Untracked));
+    }
+
+    #[test]
+    fn forgets_idle_peers() {
+      let mut limiter = RateLimiter::new(Some(RateLimit::new(2, 1000)), None, 16);
+      let now = Instant::now();
+      limiter.admit(addr(1), 10, now).unwrap();
+      limiter.admit(addr(2), 10, now + Duration::from_millis(900)).unwrap();
+      assert_eq!(limiter.tracked(), 2);
+      limiter.admit(addr(2), 10, now + Duration::from_millis(1500)).unwrap();
+      assert_eq!(limiter.tracked(), 1);
+    }
   }
 
   mod external_acks {
@@ -261,4 +903,77 @@ mod test {
       assert!(record.is_empty());
     }
   }
+
+  mod path_mtu {
+    use super::*;
+
+    #[test]
+    fn fixed_is_settled() {
+      let mtu = PathMtu::fixed(1464);
+      assert!(mtu.is_settled());
+      assert_eq!(mtu.next_probe(), None);
+      assert_eq!(mtu.max_payload_size(), 1452);
+    }
+
+    #[test]
+    fn searches_between_bounds() {
+      let mut mtu = PathMtu::search(1200, 1500);
+      assert_eq!(mtu.next_probe(), Some(1350));
+
+      mtu.probing(0, 1350);
+      assert_eq!(mtu.next_probe(), None);
+      for seq in 1..PROBE_ATTEMPTS as u16 {
+        mtu.probe_failed();
+        assert_eq!(mtu.next_probe(), Some(1350));
+        mtu.probing(seq, 1350);
+      }
+      mtu.probe_failed();
+      assert_eq!(mtu.confirmed, 1200);
+      assert_eq!(mtu.next_probe(), Some(1275));
+
+      mtu.probing(1, 1275);
+      mtu.probe_acked();
+      assert_eq!(mtu.confirmed, 1275);
+      assert_eq!(mtu.next_probe(), Some(1312));
+    }
+
+    #[test]
+    fn settles_within_resolution() {
+      let mut mtu = PathMtu::search(1200, 9000);
+      let mut seq = 0;
+      while let Some(size) = mtu.next_probe() {
+        mtu.probing(seq, size);
+        if size <= 1400 { mtu.probe_acked() } else { mtu.probe_failed() }
+        seq += 1;
+      }
+      assert!(mtu.confirmed <= 1400);
+      assert!(mtu.confirmed + PROBE_RESOLUTION > 1400);
+    }
+
+    #[test]
+    fn lost_probes_are_retried() {
+      let mut mtu = PathMtu::search(1200, 1500);
+      mtu.probing(0, 1350);
+      mtu.probe_failed();
+      mtu.probing(1, 1350);
+      mtu.probe_acked();
+      assert_eq!(mtu.confirmed, 1350);
+
+      // A success in between starts the count over
+      mtu.probing(2, 1425);
+      mtu.probe_failed();
+      mtu.probing(3, 1425);
+      mtu.probe_failed();
+      assert_eq!(mtu.next_probe(), Some(1425));
+    }
+
+    #[test]
+    fn cancelled_probes_are_retried() {
+      let mut mtu = PathMtu::search(1200, 1500);
+      mtu.probing(0, 1350);
+      mtu.probe_cancelled();
+      assert_eq!(mtu.confirmed, 1200);
+      assert_eq!(mtu.next_probe(), Some(1350));
+    }
+  }
 }
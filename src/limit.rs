@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+
+use std::net::SocketAddr;
+
+use std::time::{Duration, Instant};
+
+/// Rates for inbound traffic, each allowing a burst of one second's worth
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+  pub packets_per_sec: u32,
+  pub bytes_per_sec: u32,
+}
+
+impl RateLimit {
+  pub fn new(packets_per_sec: u32, bytes_per_sec: u32) -> RateLimit {
+    RateLimit { packets_per_sec, bytes_per_sec }
+  }
+}
+
+/// Why a datagram was dropped by a `RateLimiter`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Limited {
+  /// The peer went over its own limit, `first` when it was under it until now
+  Peer { first: bool },
+  /// All peers together went over the global limit
+  Global,
+  /// As many peers as allowed are tracked already and there is no global limit to fall back on
+  Untracked,
+}
+
+/// Token buckets for inbound datagrams, per peer and for the socket as a whole
+///
+/// Peers that stay idle long enough for their buckets to fill up again are forgotten, so a
+/// flood from many addresses does not grow the table for long. At most `max_peers` get buckets
+/// of their own; datagrams from any others are held to the global limit alone, and refused
+/// without one.
+#[derive(Debug)]
+pub struct RateLimiter {
+  peer_limit: Option<RateLimit>,
+  global: Option<Buckets>,
+  peers: HashMap<SocketAddr, PeerBuckets>,
+  max_peers: usize,
+  last_prune: Instant,
+}
+
+impl RateLimiter {
+  pub fn new(peer_limit: Option<RateLimit>, global_limit: Option<RateLimit>, max_peers: usize) -> RateLimiter {
+    let now = Instant::now();
+    RateLimiter {
+      peer_limit,
+      global: global_limit.map(|limit| Buckets::new(limit, now)),
+      peers: HashMap::new(),
+      max_peers,
+      last_prune: now,
+    }
+  }
+
+  /// Takes tokens for a datagram of `len` bytes from `addr`, unless a limit refuses it
+  ///
+  /// Nothing is taken from either bucket when one of them refuses.
+  pub fn admit(&mut self, addr: SocketAddr, len: usize, now: Instant) -> Result<(), Limited> {
+    if self.peer_limit.is_none() && self.global.is_none() {
+      return Ok(());
+    }
+    if now.duration_since(self.last_prune) >= Duration::from_secs(1) {
+      self.peers.retain(|_, peer| now.duration_since(peer.buckets.last_refill) < Duration::from_secs(1));
+      self.last_prune = now;
+    }
+
+    let untracked = self.peers.len() >= self.max_peers && !self.peers.contains_key(&addr);
+    let peer = match self.peer_limit {
+      Some(_) if untracked && self.global.is_none() => return Err(Limited::Untracked),
+      Some(limit) if !untracked => {
+        let peer = self.peers.entry(addr).or_insert_with(|| PeerBuckets { buckets: Buckets::new(limit, now), over: false });
+        peer.buckets.refill(now);
+        if !peer.buckets.allows(len) {
+          let first = !peer.over;
+          peer.over = true;
+          return Err(Limited::Peer { first });
+        }
+        Some(peer)
+      },
+      _ => None,
+    };
+    if let Some(ref mut global) = self.global {
+      global.refill(now);
+      if !global.allows(len) {
+        return Err(Limited::Global);
+      }
+      global.take(len);
+    }
+    if let Some(peer) = peer {
+      peer.buckets.take(len);
+      peer.over = false;
+    }
+    Ok(())
+  }
+
+  /// Number of peers with buckets
+  pub fn tracked(&self) -> usize {
+    self.peers.len()
+  }
+}
+
+#[derive(Debug)]
+struct PeerBuckets {
+  buckets: Buckets,
+  /// Whether the last datagram was refused
+  over: bool,
+}
+
+#[derive(Debug)]
+struct Buckets {
+  packets: TokenBucket,
+  bytes: TokenBucket,
+  last_refill: Instant,
+}
+
+impl Buckets {
+  fn new(limit: RateLimit, now: Instant) -> Buckets {
+    Buckets {
+      packets: TokenBucket::full(limit.packets_per_sec as f64),
+      bytes: TokenBucket::full(limit.bytes_per_sec as f64),
+      last_refill: now,
+    }
+  }
+
+  fn refill(&mut self, now: Instant) {
+    let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+    self.packets.refill(elapsed);
+    self.bytes.refill(elapsed);
+    self.last_refill = now;
+  }
+
+  fn allows(&self, len: usize) -> bool {
+    self.packets.tokens >= 1.0 && self.bytes.tokens >= len as f64
+  }
+
+  fn take(&mut self, len: usize) {
+    self.packets.tokens -= 1.0;
+    self.bytes.tokens -= len as f64;
+  }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+  /// Tokens added per second, and the most the bucket holds
+  rate: f64,
+  tokens: f64,
+}
+
+impl TokenBucket {
+  fn full(rate: f64) -> TokenBucket {
+    TokenBucket { rate, tokens: rate }
+  }
+
+  fn refill(&mut self, elapsed: f64) {
+    self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+  }
+}
@@ -1,29 +1,161 @@
 use addr::ToSingleSocketAddr;
 
+use pool::{BufferPool, PooledBuffer};
+
+use std::fmt;
+
+use std::hash::{Hash, Hasher};
+
 use std::io::{self, Cursor};
 
 use std::net::SocketAddr;
 
+use std::ops::Deref;
+
+use std::sync::Arc;
+
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 
 /**
- * Maximum transmission unit of a gaffer payload
+ * Largest datagram sent by default, gaffer header included
  *
- * Derived from ethernet_mtu - ipv6_header_size - udp_header_size - gaffer_header_size
- *       1452 = 1500         - 40               - 8               - 8
+ * Derived from ethernet_mtu - ipv6_header_size - udp_header_size
+ *       1452 = 1500         - 40               - 8
  *
  * This is not strictly guaranteed -- there may be less room in an ethernet frame than this due to
  * variability in ipv6 header size.
  */
-pub const GAFFER_MTU: usize = 1452; /* bytes */
+pub const GAFFER_DATAGRAM_SIZE: usize = 1452; /* bytes */
+
+/**
+ * Maximum transmission unit of a gaffer payload
+ *
+ * Derived from datagram_size - gaffer_header_size
+ *       1440 = 1452          - 12
+ */
+pub const GAFFER_MTU: usize = GAFFER_DATAGRAM_SIZE - GAFFER_HEADER_SIZE; /* bytes */
+
+/// Version of the wire format, the first byte of every datagram
+///
+/// Bumped whenever the header or the meaning of a packet kind changes, so peers running an
+/// incompatible build refuse each other's datagrams instead of misreading them.
+pub const GAFFER_VERSION: u8 = 1;
+
+/// Size of the header in front of every gaffer payload: version, kind, seq, ack_seq, ack_field
+/// and message_id
+pub const GAFFER_HEADER_SIZE: usize = 12; /* bytes */
+
+/// Payload bytes of a `Hello`, as many as a challenge cookie so the challenge is never larger
+pub const HELLO_PADDING: usize = 24; /* bytes */
+
+/// Bytes carried by a packet
+///
+/// Cloning shares the bytes instead of copying them, so one payload can be sent to many peers
+/// and kept around for resends without new allocations. Built from a `Vec<u8>` without copying.
+/// Received payloads live in buffers from the socket's pool, and go back to it once the last
+/// clone is dropped.
+#[derive(Clone)]
+pub struct GafferPayload(Arc<PooledBuffer>);
+
+impl GafferPayload {
+  pub fn new() -> GafferPayload {
+    GafferPayload::from(Vec::new())
+  }
+
+  /// Bytes that return to `pool` when the last clone is dropped
+  pub fn pooled(bytes: Vec<u8>, pool: &BufferPool) -> GafferPayload {
+    GafferPayload(Arc::new(PooledBuffer::new(bytes, pool)))
+  }
+
+  /// The bytes as a `Vec`, only copied if the payload is still shared
+  ///
+  /// Taking the bytes keeps them from going back to a pool.
+  pub fn into_vec(self) -> Vec<u8> {
+    Arc::try_unwrap(self.0)
+      .map(PooledBuffer::into_vec)
+      .unwrap_or_else(|shared| shared.bytes().clone())
+  }
+}
+
+impl Default for GafferPayload {
+  fn default() -> GafferPayload {
+    GafferPayload::new()
+  }
+}
 
-/// TODO: consider slice
-pub type GafferPayload = Vec<u8>;
+impl Deref for GafferPayload {
+  type Target = [u8];
+
+  fn deref(&self) -> &[u8] {
+    self.0.bytes()
+  }
+}
+
+impl AsRef<[u8]> for GafferPayload {
+  fn as_ref(&self) -> &[u8] {
+    self.0.bytes()
+  }
+}
+
+impl From<Vec<u8>> for GafferPayload {
+  fn from(bytes: Vec<u8>) -> GafferPayload {
+    GafferPayload(Arc::new(PooledBuffer::unpooled(bytes)))
+  }
+}
+
+impl<'a> From<&'a [u8]> for GafferPayload {
+  fn from(bytes: &'a [u8]) -> GafferPayload {
+    GafferPayload::from(bytes.to_vec())
+  }
+}
+
+impl fmt::Debug for GafferPayload {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    self.0.bytes().fmt(f)
+  }
+}
+
+impl PartialEq for GafferPayload {
+  fn eq(&self, other: &GafferPayload) -> bool {
+    self.0.bytes() == other.0.bytes()
+  }
+}
+
+impl Eq for GafferPayload {}
+
+impl Hash for GafferPayload {
+  fn hash<H: Hasher>(&self, state: &mut H) {
+    self.0.bytes().hash(state)
+  }
+}
+
+impl PartialEq<Vec<u8>> for GafferPayload {
+  fn eq(&self, other: &Vec<u8>) -> bool {
+    self.0.bytes() == other
+  }
+}
+
+impl PartialEq<GafferPayload> for Vec<u8> {
+  fn eq(&self, other: &GafferPayload) -> bool {
+    self == other.0.bytes()
+  }
+}
+
+impl<'a> PartialEq<&'a [u8]> for GafferPayload {
+  fn eq(&self, other: &&'a [u8]) -> bool {
+    self.0.bytes().as_slice() == *other
+  }
+}
 
 #[derive(Clone, PartialEq, Eq, Debug)]
 pub struct GafferPacket {
   pub addr: SocketAddr,
-  pub payload: GafferPayload
+  pub payload: GafferPayload,
+  /// Identity kept across resends, so the peer can drop a payload it already has
+  ///
+  /// Assigned when the packet is first sent. `None` for new packets and for received ones, so a
+  /// received packet can be passed on to another peer.
+  pub message_id: Option<u16>,
 }
 
 impl GafferPacket {
@@ -31,45 +163,202 @@ impl GafferPacket {
     GafferPacket::new("0.0.0.0:7878", GafferPayload::new())
   }
 
-  pub fn new<A: ToSingleSocketAddr>(addr: A, payload: GafferPayload) -> GafferPacket {
+  pub fn new<A: ToSingleSocketAddr, P: Into<GafferPayload>>(addr: A, payload: P) -> GafferPacket {
     let first_addr = addr.to_single_socket_addr().unwrap();
-    GafferPacket { addr: first_addr, payload: payload }
+    GafferPacket { addr: first_addr, payload: payload.into(), message_id: None }
   }
 }
 
 
+/// What a packet carries, the first byte of the header
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PacketKind {
+  /// An application payload
+  Data,
+  /// Padding sent to test whether a datagram of its size reaches the peer
+  Probe,
+  /// A client asking to be let in, carrying the private part of its connect token
+  Connect,
+  /// A cookie a server wants echoed back before it keeps any state for a new address
+  Challenge,
+  /// The echoed cookie
+  Response,
+  /// A server turning a new peer away because it has `GafferConfig::max_connections` already
+  Full,
+  /// A peer leaving, carrying a `DisconnectReason`
+  ///
+  /// Sequenced and encrypted like data, but never resent; it goes out a few times instead.
+  /// Only encryption makes it trustworthy: from a peer without keys, anyone who can guess a
+  /// sequence number inside the window can forge one and tear the connection down.
+  Disconnect,
+  /// Padding sent to a peer that has not answered yet
+  ///
+  /// A server that challenges new addresses only answers datagrams at least as large as the
+  /// challenge, so clients whose packets are all smaller would never get in without it. Ignored
+  /// by everyone else.
+  Hello,
+  /// Acks for a peer we have nothing else to send to
+  ///
+  /// Sequenced and encrypted like data so it cannot be forged or replayed, but never resent or
+  /// acked itself. See `GafferState::queue_acks`.
+  Ack,
+}
+
+impl PacketKind {
+  pub fn to_u8(self) -> u8 {
+    match self {
+      PacketKind::Data => 0,
+      PacketKind::Probe => 1,
+      PacketKind::Connect => 2,
+      PacketKind::Challenge => 3,
+      PacketKind::Response => 4,
+      PacketKind::Full => 5,
+      PacketKind::Disconnect => 6,
+      PacketKind::Hello => 7,
+      PacketKind::Ack => 8,
+    }
+  }
+
+  pub fn from_u8(byte: u8) -> Option<PacketKind> {
+    match byte {
+      0 => Some(PacketKind::Data),
+      1 => Some(PacketKind::Probe),
+      2 => Some(PacketKind::Connect),
+      3 => Some(PacketKind::Challenge),
+      4 => Some(PacketKind::Response),
+      5 => Some(PacketKind::Full),
+      6 => Some(PacketKind::Disconnect),
+      7 => Some(PacketKind::Hello),
+      8 => Some(PacketKind::Ack),
+      _ => None,
+    }
+  }
+}
+
+/// Why a peer said it is leaving
+///
+/// A server turning a new peer away for being full answers with a `Full` reply instead, see
+/// `GafferEvent::ServerFull`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DisconnectReason {
+  /// The user on the other side chose to leave
+  UserQuit,
+  /// The other side removed us
+  Kicked,
+  /// The other side stopped hearing from us
+  Timeout,
+  /// The other side speaks a version of the app protocol we do not
+  ProtocolMismatch,
+  /// An app defined reason
+  Custom(u8),
+  /// The other side's socket was shut down
+  Shutdown,
+}
+
+impl DisconnectReason {
+  /// The payload of a `Disconnect` packet
+  pub fn to_bytes(self) -> Vec<u8> {
+    match self {
+      DisconnectReason::UserQuit => vec![0],
+      DisconnectReason::Kicked => vec![1],
+      DisconnectReason::Timeout => vec![2],
+      DisconnectReason::ProtocolMismatch => vec![3],
+      DisconnectReason::Custom(code) => vec![4, code],
+      DisconnectReason::Shutdown => vec![5],
+    }
+  }
+
+  pub fn from_bytes(bytes: &[u8]) -> Option<DisconnectReason> {
+    match *bytes {
+      [0] => Some(DisconnectReason::UserQuit),
+      [1] => Some(DisconnectReason::Kicked),
+      [2] => Some(DisconnectReason::Timeout),
+      [3] => Some(DisconnectReason::ProtocolMismatch),
+      [4, code] => Some(DisconnectReason::Custom(code)),
+      [5] => Some(DisconnectReason::Shutdown),
+      _ => None,
+    }
+  }
+}
+
 #[derive(Clone, PartialEq, Eq, Debug)]
 pub struct CompleteGafferPacket {
+  pub kind: PacketKind,
   pub seq: u16,
   pub ack_seq: u16,
   pub ack_field: u32,
+  pub message_id: u16,
   pub payload: GafferPayload
 }
 
 impl CompleteGafferPacket {
   pub fn serialized(&self) -> Vec<u8> {
     let mut wtr = Vec::new();
+    self.serialize_into(&mut wtr);
+    wtr
+  }
+
+  /// Appends the encoded packet to `wtr`, to encode into a reused buffer
+  pub fn serialize_into(&self, wtr: &mut Vec<u8>) {
+    wtr.write_u8(GAFFER_VERSION).unwrap();
+    wtr.write_u8(self.kind.to_u8()).unwrap();
     wtr.write_u16::<BigEndian>(self.seq).unwrap();
     wtr.write_u16::<BigEndian>(self.ack_seq).unwrap();
     wtr.write_u32::<BigEndian>(self.ack_field).unwrap();
-    wtr.append(&mut self.payload.clone());
-    wtr
+    wtr.write_u16::<BigEndian>(self.message_id).unwrap();
+    wtr.extend_from_slice(&self.payload);
   }
 
   pub fn deserialize(mut bytes: Vec<u8>) -> io::Result<CompleteGafferPacket> {
-    let payload = bytes.split_off(8);
-    let mut rdr = Cursor::new(bytes);
+    let (kind, seq, ack_seq, ack_field, message_id) = read_header(&bytes)?;
+    let payload = bytes.split_off(GAFFER_HEADER_SIZE);
+
+    Ok(CompleteGafferPacket {
+      kind,
+      seq,
+      ack_seq,
+      ack_field,
+      message_id,
+      payload: payload.into()
+    })
+  }
 
-    let seq = try!(rdr.read_u16::<BigEndian>());
-    let ack_seq = try!(rdr.read_u16::<BigEndian>());
-    let ack_field = try!(rdr.read_u32::<BigEndian>());
+  /// Decodes a received datagram, copying the payload into a buffer from `pool`
+  pub fn deserialize_pooled(bytes: &[u8], pool: &BufferPool) -> io::Result<CompleteGafferPacket> {
+    let (kind, seq, ack_seq, ack_field, message_id) = read_header(bytes)?;
+    let mut payload = pool.take();
+    payload.extend_from_slice(&bytes[GAFFER_HEADER_SIZE..]);
 
     Ok(CompleteGafferPacket {
-      seq: seq,
-      ack_seq: ack_seq,
-      ack_field: ack_field,
-      payload: payload
+      kind,
+      seq,
+      ack_seq,
+      ack_field,
+      message_id,
+      payload: GafferPayload::pooled(payload, pool)
     })
   }
 }
 
+/// Reads the header fields at the start of a datagram: kind, seq, ack_seq, ack_field and
+/// message_id
+///
+/// Datagrams from another version of the wire format are refused.
+pub fn read_header(bytes: &[u8]) -> io::Result<(PacketKind, u16, u16, u32, u16)> {
+  if bytes.len() < GAFFER_HEADER_SIZE {
+    return Err(io::Error::new(io::ErrorKind::InvalidData, "datagram is shorter than the gaffer header"));
+  }
+  let mut rdr = Cursor::new(bytes);
+
+  if rdr.read_u8()? != GAFFER_VERSION {
+    return Err(io::Error::new(io::ErrorKind::InvalidData, "unsupported gaffer protocol version"));
+  }
+  let kind = PacketKind::from_u8(rdr.read_u8()?).ok_or_else(|| {
+    io::Error::new(io::ErrorKind::InvalidData, "unknown gaffer packet kind")
+  })?;
+  let seq = rdr.read_u16::<BigEndian>()?;
+  let ack_seq = rdr.read_u16::<BigEndian>()?;
+  let ack_field = rdr.read_u32::<BigEndian>()?;
+  let message_id = rdr.read_u16::<BigEndian>()?;
+  Ok((kind, seq, ack_seq, ack_field, message_id))
+}
@@ -0,0 +1,112 @@
+use std::fmt;
+
+use std::mem;
+
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Size of the buffers received payloads this small are decoded into
+///
+/// Most payloads are far smaller than the largest datagram, so they come from a second pool
+/// instead of each holding a buffer sized for the path MTU.
+pub const SMALL_BUFFER_SIZE: usize = 256;
+
+/// Reusable byte buffers for encoding and decoding datagrams
+///
+/// Clones share the same buffers, so payloads handed out on one thread can return them from
+/// another. At most `capacity` idle buffers are kept, any beyond that are freed.
+#[derive(Clone)]
+pub struct BufferPool {
+  inner: Arc<PoolInner>,
+}
+
+struct PoolInner {
+  buffers: Mutex<Vec<Vec<u8>>>,
+  capacity: usize,
+  buffer_size: usize,
+}
+
+impl BufferPool {
+  /// A pool keeping up to `capacity` buffers, each allocated to hold `buffer_size` bytes
+  pub fn new(capacity: usize, buffer_size: usize) -> BufferPool {
+    BufferPool {
+      inner: Arc::new(PoolInner {
+        buffers: Mutex::new(Vec::with_capacity(capacity)),
+        capacity,
+        buffer_size,
+      })
+    }
+  }
+
+  /// An empty buffer, reused if one is idle
+  pub fn take(&self) -> Vec<u8> {
+    self.buffers().pop().unwrap_or_else(|| Vec::with_capacity(self.inner.buffer_size))
+  }
+
+  /// Returns a buffer for reuse
+  ///
+  /// Buffers that cannot hold `buffer_size` bytes are freed, so a taken buffer never has to grow.
+  pub fn give(&self, mut buffer: Vec<u8>) {
+    if buffer.capacity() == 0 || buffer.capacity() < self.inner.buffer_size {
+      return;
+    }
+    let mut buffers = self.buffers();
+    if buffers.len() < self.inner.capacity {
+      buffer.clear();
+      buffers.push(buffer);
+    }
+  }
+
+  /// Number of idle buffers waiting to be reused
+  pub fn idle(&self) -> usize {
+    self.buffers().len()
+  }
+
+  /// A panic on another thread holding the lock leaves the list of buffers intact
+  fn buffers(&self) -> MutexGuard<'_, Vec<Vec<u8>>> {
+    self.inner.buffers.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+  }
+}
+
+impl fmt::Debug for BufferPool {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("BufferPool")
+      .field("idle", &self.idle())
+      .field("capacity", &self.inner.capacity)
+      .field("buffer_size", &self.inner.buffer_size)
+      .finish()
+  }
+}
+
+/// Bytes that go back to their pool when dropped, if they came from one
+pub struct PooledBuffer {
+  bytes: Vec<u8>,
+  pool: Option<BufferPool>,
+}
+
+impl PooledBuffer {
+  pub fn new(bytes: Vec<u8>, pool: &BufferPool) -> PooledBuffer {
+    PooledBuffer { bytes, pool: Some(pool.clone()) }
+  }
+
+  /// Bytes with no pool to return to
+  pub fn unpooled(bytes: Vec<u8>) -> PooledBuffer {
+    PooledBuffer { bytes, pool: None }
+  }
+
+  pub fn bytes(&self) -> &Vec<u8> {
+    &self.bytes
+  }
+
+  /// Takes the bytes out, they no longer go back to the pool
+  pub fn into_vec(mut self) -> Vec<u8> {
+    mem::take(&mut self.bytes)
+  }
+}
+
+impl Drop for PooledBuffer {
+  fn drop(&mut self) {
+    if let Some(ref pool) = self.pool {
+      pool.give(mem::take(&mut self.bytes));
+    }
+  }
+}
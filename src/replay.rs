@@ -0,0 +1,119 @@
+/// Number of sequence numbers behind the newest that are still tracked by default
+pub const REPLAY_WINDOW_SIZE: u64 = 64;
+
+/// Sliding window of sequence numbers already received from a peer
+///
+/// 16 bit sequence numbers from the header are extended to 64 bits relative to the newest one
+/// seen, so the window keeps working after they wrap. Anything older than the window, or already
+/// marked as seen, is a replay.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReplayWindow {
+  newest: Option<u64>,
+  /// Bit `n % size` is set when `n` was seen, for every `n` in the window
+  seen: Vec<u64>,
+  size: u64,
+}
+
+impl ReplayWindow {
+  pub fn new() -> ReplayWindow {
+    ReplayWindow::with_size(REPLAY_WINDOW_SIZE)
+  }
+
+  /// A window tracking `size` sequence numbers, rounded up to a multiple of 64
+  pub fn with_size(size: u64) -> ReplayWindow {
+    let words = size.max(1).div_ceil(64);
+    ReplayWindow { newest: None, seen: vec![0; words as usize], size: words * 64 }
+  }
+
+  /// Number of sequence numbers tracked behind the newest one
+  pub fn size(&self) -> u64 {
+    self.size
+  }
+
+  /// Newest extended sequence number seen
+  pub fn newest(&self) -> Option<u64> {
+    self.newest
+  }
+
+  /// The extended sequence number closest to the newest one seen with `seq` as its low bits
+  pub fn extend(&self, seq: u16) -> u64 {
+    let newest = match self.newest {
+      Some(newest) => newest,
+      None => return seq as u64,
+    };
+    let candidate = (newest & !0xffff) | seq as u64;
+    if candidate + 0x8000 <= newest {
+      candidate + 0x10000
+    } else if candidate > newest + 0x8000 && candidate >= 0x10000 {
+      candidate - 0x10000
+    } else {
+      candidate
+    }
+  }
+
+  /// Whether `extended` is new, neither seen before nor older than the window
+  pub fn check(&self, extended: u64) -> bool {
+    match self.newest {
+      None => true,
+      Some(newest) if extended > newest => true,
+      Some(newest) => newest - extended < self.size && !self.is_set(extended),
+    }
+  }
+
+  /// Whether `extended` is inside the window and was seen, unlike `check` saying nothing of
+  /// ones older than the window
+  pub fn contains(&self, extended: u64) -> bool {
+    match self.newest {
+      Some(newest) if extended <= newest => newest - extended < self.size && self.is_set(extended),
+      _ => false,
+    }
+  }
+
+  /// Marks `extended` as seen, only once it passed `check` and was authenticated
+  pub fn accept(&mut self, extended: u64) {
+    match self.newest {
+      Some(newest) if extended <= newest => {
+        if newest - extended < self.size {
+          self.set(extended);
+        }
+      },
+      Some(newest) => {
+        if extended - newest >= self.size {
+          self.seen.iter_mut().for_each(|word| *word = 0);
+        } else {
+          // Slots being moved into the window still hold bits from a lap ago
+          for skipped in newest + 1..extended {
+            self.clear(skipped);
+          }
+        }
+        self.set(extended);
+        self.newest = Some(extended);
+      },
+      None => {
+        self.set(extended);
+        self.newest = Some(extended);
+      },
+    }
+  }
+
+  fn is_set(&self, extended: u64) -> bool {
+    let slot = extended % self.size;
+    self.seen[(slot / 64) as usize] & (1 << (slot % 64)) != 0
+  }
+
+  fn set(&mut self, extended: u64) {
+    let slot = extended % self.size;
+    self.seen[(slot / 64) as usize] |= 1 << (slot % 64);
+  }
+
+  fn clear(&mut self, extended: u64) {
+    let slot = extended % self.size;
+    self.seen[(slot / 64) as usize] &= !(1 << (slot % 64));
+  }
+}
+
+impl Default for ReplayWindow {
+  fn default() -> ReplayWindow {
+    ReplayWindow::new()
+  }
+}
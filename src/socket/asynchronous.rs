@@ -0,0 +1,537 @@
+use std::io;
+
+use std::collections::VecDeque;
+
+use std::future::Future;
+
+use std::net::SocketAddr;
+
+use std::mem;
+
+use std::time::{Duration, Instant};
+
+use std::pin::Pin;
+
+use std::task::{Context, Poll};
+
+use tokio::io::ReadBuf;
+use tokio::net::UdpSocket;
+use tokio::time::{self, Sleep};
+
+use config::GafferConfig;
+
+use connection::Connection;
+
+use filter::{AddressFilter, IpRange};
+
+#[cfg(feature = "crypto")]
+use crypto::PacketKeys;
+
+use event::GafferEvent;
+
+use socket::{GafferState, PartialSend, ShutdownReport, SHUTDOWN_POLL};
+use addr::ToSingleSocketAddr;
+
+use packet::{DisconnectReason, GafferPacket};
+
+use stats::GafferStats;
+
+#[cfg(feature = "crypto")]
+use token::ConnectToken;
+
+/// A `GafferSocket` driven by std futures
+///
+/// Built on tokio's reactor, so it must be bound and polled from inside a tokio runtime.
+pub struct GafferSocket {
+  udp_socket: UdpSocket,
+  state: GafferState,
+  recv_buffer: Vec<u8>,
+}
+
+impl GafferSocket {
+  pub fn bind<A: ToSingleSocketAddr>(addr: A) -> io::Result<Self> {
+    GafferSocket::bind_with_config(addr, GafferConfig::new())
+  }
+
+  /// Bind with socket options and protocol settings from `config`
+  pub fn bind_with_config<A: ToSingleSocketAddr>(addr: A, config: GafferConfig) -> io::Result<Self> {
+    let first_addr = addr.to_single_socket_addr()?;
+    let sock = config.bind_udp(first_addr)?;
+    sock.set_nonblocking(true)?;
+    UdpSocket::from_std(sock).and_then(|sock| {
+      let mut state = GafferState::with_config(config);
+      state.set_local_addr(sock.local_addr()?);
+      Ok(GafferSocket {
+        udp_socket: sock,
+        recv_buffer: vec![0; state.recv_buffer_size()],
+        state,
+      })
+    })
+  }
+
+  /// The address the socket is bound to, useful after binding to port 0
+  pub fn local_addr(&self) -> io::Result<SocketAddr> {
+    self.udp_socket.local_addr()
+  }
+
+  /// Counters for received traffic that was discarded
+  pub fn stats(&self) -> &GafferStats {
+    self.state.stats()
+  }
+
+  /// Addresses of every peer with a connection
+  pub fn peers(&self) -> Vec<SocketAddr> {
+    self.state.peers()
+  }
+
+  pub fn connection(&self, addr: &SocketAddr) -> Option<&Connection> {
+    self.state.connection(addr)
+  }
+
+  /// Drops all state for a peer, see `GafferState::forget`
+  pub fn forget_peer(&mut self, addr: &SocketAddr) -> Option<Connection> {
+    self.state.forget(addr)
+  }
+
+  /// Tells a peer we are leaving and forgets it, see `GafferState::disconnect`
+  pub fn disconnect(&mut self, addr: SocketAddr, reason: DisconnectReason) -> io::Result<Connection> {
+    let connection = self.state.disconnect(addr, reason)?;
+    self.send_control();
+    Ok(connection)
+  }
+
+  /// Which addresses datagrams are accepted from, see `GafferState::filter_mut`
+  pub fn filter_mut(&mut self) -> &mut AddressFilter {
+    self.state.filter_mut()
+  }
+
+  /// Shuts a range out for `duration` and forgets its connections, see `GafferState::ban`
+  pub fn ban(&mut self, range: IpRange, duration: Duration) -> Vec<SocketAddr> {
+    self.state.ban(range, duration)
+  }
+
+  /// Encrypts everything to and from a peer, see `GafferState::set_keys`
+  #[cfg(feature = "crypto")]
+  pub fn set_peer_keys(&mut self, addr: SocketAddr, keys: &PacketKeys) -> io::Result<()> {
+    self.state.set_keys(addr, keys)
+  }
+
+  /// Connects to a server with a token from the backend, see `GafferState::connect`
+  ///
+  /// The request goes out right away and again on every `update` until the server answers.
+  #[cfg(feature = "crypto")]
+  pub fn connect(&mut self, server: SocketAddr, token: &ConnectToken) -> io::Result<()> {
+    self.state.connect(server, token)?;
+    self.send_connects()
+  }
+
+  /// Resend dropped packets for every known connection
+  ///
+  /// Meant to be awaited periodically, say on a `tokio::time::interval` tick, see
+  /// `blocking::GafferSocket::update`. Resolves as `poll_update` does.
+  pub fn update(&mut self) -> UpdateFuture<'_> {
+    UpdateFuture { socket: self }
+  }
+
+  /// Resend dropped packets, path MTU probes and connect requests once the socket is writable
+  ///
+  /// Only waits for the socket to become writable, then sends what goes out without blocking.
+  /// Resends that would block stay queued for the next call, and probes and connect requests
+  /// that would block are retried on a later one. Returns the number of packets resent.
+  pub fn poll_update(&mut self, cx: &mut Context) -> Poll<io::Result<usize>> {
+    match self.udp_socket.poll_send_ready(cx) {
+      Poll::Pending => return Poll::Pending,
+      Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+      Poll::Ready(Ok(())) => {},
+    }
+    Poll::Ready(self.send_pending())
+  }
+
+  fn send_pending(&mut self) -> io::Result<usize> {
+    let mut resent = 0;
+    for addr in self.state.addrs_with_dropped_packets() {
+      resent += self.resend_dropped(addr)?;
+    }
+    for (addr, probe) in self.state.preprocess_probes() {
+      let result = self.udp_socket.try_send_to(&probe, addr);
+      self.state.probe_sent(addr, &result);
+      self.state.recycle(probe);
+    }
+    self.send_connects()?;
+    self.state.queue_hellos();
+    self.state.queue_acks();
+    self.send_control();
+    Ok(resent)
+  }
+
+  /// Stops sending and waits up to `timeout` for packets in flight to be confirmed
+  ///
+  /// Resolves like `blocking::GafferSocket::shutdown` does, with the packets never confirmed and
+  /// those received meanwhile. New sends are refused from the start, even if the future is
+  /// dropped before it resolves. Needs tokio's timer enabled on the runtime.
+  pub fn shutdown(&mut self, timeout: Duration) -> ShutdownFuture<'_> {
+    self.state.begin_shutdown();
+    ShutdownFuture {
+      deadline: Instant::now() + timeout,
+      timer: Box::pin(time::sleep(Duration::ZERO)),
+      received: Vec::new(),
+      unconfirmed: None,
+      socket: self,
+    }
+  }
+
+  /// Largest payload known to reach `addr` in one datagram, see `GafferState::path_max_payload`
+  pub fn path_max_payload(&self, addr: &SocketAddr) -> usize {
+    self.state.path_max_payload(addr)
+  }
+
+  fn resend_dropped(&mut self, addr: SocketAddr) -> io::Result<usize> {
+    let packets = self.state.dropped_packets(addr);
+    let count = packets.len();
+    let datagrams = self.state.preprocess_batch(packets);
+    let mut resent = 0;
+    let mut failure = None;
+    for (destination, datagram) in &datagrams {
+      match self.udp_socket.try_send_to(datagram, *destination) {
+        Ok(_) => resent += 1,
+        Err(err) => {
+          failure = Some(err);
+          break;
+        },
+      }
+    }
+    for (_, datagram) in datagrams {
+      self.state.recycle(datagram);
+    }
+    let requeued = count - resent;
+    self.state.requeue_unsent(addr, requeued);
+    match failure {
+      Some(ref err) if err.kind() == io::ErrorKind::WouldBlock => Ok(resent),
+      Some(err) => Err(PartialSend { addr, resent, requeued, cause: err }.into()),
+      None => Ok(resent),
+    }
+  }
+
+  fn send_connects(&mut self) -> io::Result<()> {
+    for (addr, datagram) in self.state.preprocess_connects() {
+      let result = self.udp_socket.try_send_to(&datagram, addr);
+      self.state.recycle(datagram);
+      match result {
+        Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {},
+        Err(err) => return Err(PartialSend { addr, resent: 0, requeued: 0, cause: err }.into()),
+        Ok(_) => {},
+      }
+    }
+    Ok(())
+  }
+
+  /// Sends the datagrams the protocol queued by itself, see `blocking::send_control`
+  fn send_control(&mut self) {
+    while let Some((addr, datagram)) = self.state.poll_control() {
+      let _ = self.udp_socket.try_send_to(&datagram, addr);
+      self.state.recycle(datagram);
+    }
+  }
+
+  /// Next connection event, such as a client being let in with a connect token
+  pub fn poll_event(&mut self) -> Option<GafferEvent> {
+    self.state.poll_event()
+  }
+
+  /// Receive a normal message
+  ///
+  /// Resolves with the next packet, see `blocking::GafferSocket::recv`.
+  pub fn recv(&mut self) -> RecvFuture<'_> {
+    RecvFuture { socket: self }
+  }
+
+  /// Poll for the next received packet
+  ///
+  /// Can be called repeatedly to treat the socket as a stream of packets.
+  pub fn poll_recv(&mut self, cx: &mut Context) -> Poll<io::Result<GafferPacket>> {
+    loop {
+      let (len, addr) = {
+        let mut buf = ReadBuf::new(&mut self.recv_buffer);
+        match self.udp_socket.poll_recv_from(cx, &mut buf) {
+          Poll::Pending => return Poll::Pending,
+          Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+          Poll::Ready(Ok(addr)) => (buf.filled().len(), addr),
+        }
+      };
+      let received = self.state.process_datagram(addr, &self.recv_buffer[..len]);
+      self.send_control();
+      match received {
+        Ok(Some(packet)) => return Poll::Ready(Ok(packet)),
+        Ok(None) => {},
+        Err(err) => return Poll::Ready(Err(err)),
+      }
+    }
+  }
+
+  /// Send a normal message
+  ///
+  /// Resolves once the dropped packets and the packet itself are sent, with the same results as
+  /// `blocking::GafferSocket::send`. Dropped packets not yet sent when the future is dropped are
+  /// kept for the next send, and so is the packet waiting for the socket at that point.
+  pub fn send(&mut self, p: GafferPacket) -> SendFuture<'_> {
+    let addr = p.addr;
+    let (dropped, packet, rejected) = match self.state.check_send(&p) {
+      Ok(()) => (self.state.dropped_packets(addr).into_iter().collect(), Some(p), None),
+      Err(err) => (VecDeque::new(), None, Some(err)),
+    };
+    SendFuture {
+      socket: self,
+      addr,
+      dropped,
+      packet,
+      encoded: None,
+      resent: 0,
+      rejected,
+    }
+  }
+}
+
+/// Future returned by `GafferSocket::recv`
+pub struct RecvFuture<'a> {
+  socket: &'a mut GafferSocket,
+}
+
+impl<'a> Future for RecvFuture<'a> {
+  type Output = io::Result<GafferPacket>;
+
+  fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+    self.get_mut().socket.poll_recv(cx)
+  }
+}
+
+/// Future returned by `GafferSocket::update`
+pub struct UpdateFuture<'a> {
+  socket: &'a mut GafferSocket,
+}
+
+impl<'a> Future for UpdateFuture<'a> {
+  type Output = io::Result<usize>;
+
+  fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+    self.get_mut().socket.poll_update(cx)
+  }
+}
+
+/// Future returned by `GafferSocket::shutdown`
+pub struct ShutdownFuture<'a> {
+  socket: &'a mut GafferSocket,
+  deadline: Instant,
+  /// Wakes the task to resend, and to send the later copies of each `Disconnect`
+  timer: Pin<Box<Sleep>>,
+  received: Vec<GafferPacket>,
+  /// Set once every peer was disconnected
+  unconfirmed: Option<Vec<GafferPacket>>,
+}
+
+impl<'a> ShutdownFuture<'a> {
+  /// Whether the timer is already up once set to `at`, registering the task to wake if not
+  fn wait_until(&mut self, at: Instant, cx: &mut Context) -> bool {
+    self.timer.as_mut().reset(at.into());
+    self.timer.as_mut().poll(cx).is_ready()
+  }
+}
+
+impl<'a> Future for ShutdownFuture<'a> {
+  type Output = io::Result<ShutdownReport>;
+
+  fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+    let this = self.get_mut();
+    while this.unconfirmed.is_none() {
+      // Errors are for single datagrams
+      loop {
+        match this.socket.poll_recv(cx) {
+          Poll::Ready(Ok(packet)) => this.received.push(packet),
+          Poll::Ready(Err(_)) => {},
+          Poll::Pending => break,
+        }
+      }
+      // Failed resends are retried next time round
+      let _ = this.socket.poll_update(cx);
+      let now = Instant::now();
+      if now >= this.deadline || !this.socket.state.has_unconfirmed() {
+        this.unconfirmed = Some(this.socket.state.finish_shutdown());
+        this.socket.send_control();
+      } else if !this.wait_until((now + SHUTDOWN_POLL).min(this.deadline), cx) {
+        return Poll::Pending;
+      }
+    }
+    // The later copies of each `Disconnect` are spaced out
+    while let Some(due) = this.socket.state.next_farewell() {
+      if !this.wait_until(due, cx) {
+        return Poll::Pending;
+      }
+      this.socket.send_control();
+    }
+    Poll::Ready(Ok(ShutdownReport {
+      unconfirmed: this.unconfirmed.take().unwrap_or_default(),
+      received: mem::take(&mut this.received),
+    }))
+  }
+}
+
+/// Future returned by `GafferSocket::send`
+pub struct SendFuture<'a> {
+  socket: &'a mut GafferSocket,
+  addr: SocketAddr,
+  dropped: VecDeque<GafferPacket>,
+  packet: Option<GafferPacket>,
+  encoded: Option<(SocketAddr, Vec<u8>)>,
+  resent: usize,
+  rejected: Option<io::Error>,
+}
+
+impl<'a> Future for SendFuture<'a> {
+  type Output = io::Result<usize>;
+
+  fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+    let this = self.get_mut();
+    if let Some(err) = this.rejected.take() {
+      return Poll::Ready(Err(err));
+    }
+    loop {
+      // Sequence numbers are only taken once a packet is actually about to go out
+      if this.encoded.is_none() {
+        let next = this.dropped.pop_front().or_else(|| this.packet.take());
+        match next {
+          Some(packet) => this.encoded = Some(this.socket.state.preprocess_packet(packet)),
+          None => return Poll::Ready(Err(io::Error::other("send polled after completion"))),
+        }
+      }
+
+      let res = {
+        let (destination, payload) = this.encoded.as_ref().unwrap();
+        match this.socket.udp_socket.poll_send_to(cx, payload, *destination) {
+          Poll::Pending => return Poll::Pending,
+          Poll::Ready(res) => res,
+        }
+      };
+      if let Some((_, datagram)) = this.encoded.take() {
+        this.socket.state.recycle(datagram);
+      }
+
+      match res {
+        Ok(len) => {
+          if this.packet.is_none() {
+            return Poll::Ready(Ok(len));
+          }
+          this.resent += 1;
+        },
+        Err(err) => {
+          let unsent: Vec<GafferPacket> = this.dropped.drain(..).collect();
+          this.packet = None;
+          let requeued = unsent.len() + 1;
+          this.socket.state.requeue_dropped(this.addr, unsent);
+          this.socket.state.requeue_unsent(this.addr, 1);
+          return Poll::Ready(Err(PartialSend { addr: this.addr, resent: this.resent, requeued, cause: err }.into()));
+        }
+      }
+    }
+  }
+}
+
+impl<'a> Drop for SendFuture<'a> {
+  fn drop(&mut self) {
+    if !self.dropped.is_empty() {
+      let unsent = self.dropped.drain(..).collect();
+      self.socket.state.requeue_dropped(self.addr, unsent);
+    }
+    // The packet waiting for the socket already took its sequence number, it goes first
+    if let Some((_, datagram)) = self.encoded.take() {
+      self.socket.state.recycle(datagram);
+      self.socket.state.requeue_unsent(self.addr, 1);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use packet::GafferPacket;
+
+  use tokio::runtime::{Builder, Runtime};
+
+  fn local_runtime() -> Runtime {
+    Builder::new_current_thread().enable_io().enable_time().build().unwrap()
+  }
+
+  #[test]
+  fn send_and_recv() {
+    let runtime = local_runtime();
+    let _guard = runtime.enter();
+    let mut send_sock = GafferSocket::bind("127.0.0.1:45222").unwrap();
+    let mut recv_sock = GafferSocket::bind("127.0.0.1:45223").unwrap();
+
+    let sent = runtime.block_on(send_sock.send(GafferPacket::new("127.0.0.1:45223", vec![1, 2, 3])));
+    assert!(sent.is_ok());
+
+    let packet = runtime.block_on(recv_sock.recv()).unwrap();
+    assert_eq!(packet.payload, vec![1, 2, 3]);
+    assert_eq!(packet.addr.to_string(), "127.0.0.1:45222");
+  }
+
+  #[test]
+  fn dropped_send_keeps_resends() {
+    let runtime = local_runtime();
+    let _guard = runtime.enter();
+    let mut sock = GafferSocket::bind("127.0.0.1:45224").unwrap();
+    let destination = "127.0.0.1:45225".parse().unwrap();
+    sock.state.requeue_dropped(destination, vec![GafferPacket::new(destination, vec![9])]);
+
+    drop(sock.send(GafferPacket::new(destination, vec![1])));
+    assert_eq!(sock.state.dropped_packets(destination).len(), 1);
+  }
+
+  #[test]
+  fn dropped_send_keeps_the_waiting_packet() {
+    let runtime = local_runtime();
+    let _guard = runtime.enter();
+    let mut sock = GafferSocket::bind("127.0.0.1:0").unwrap();
+    let destination = "127.0.0.1:45225".parse().unwrap();
+    sock.state.requeue_dropped(destination, vec![GafferPacket::new(destination, vec![9])]);
+
+    let mut send = sock.send(GafferPacket::new(destination, vec![1]));
+    // As if the socket was not ready for the first resend
+    let first = send.dropped.pop_front().unwrap();
+    send.encoded = Some(send.socket.state.preprocess_packet(first));
+    drop(send);
+    let payloads: Vec<Vec<u8>> = sock.state.dropped_packets(destination).into_iter().map(|p| p.payload.into_vec()).collect();
+    assert_eq!(payloads, vec![vec![9]]);
+    assert!(!sock.state.has_unconfirmed());
+  }
+
+  #[test]
+  fn shutdown_waits_for_acks() {
+    let runtime = local_runtime();
+    let _guard = runtime.enter();
+    let mut sock = GafferSocket::bind("127.0.0.1:0").unwrap();
+    let mut peer = GafferSocket::bind("127.0.0.1:0").unwrap();
+    let peer_addr = peer.local_addr().unwrap();
+
+    runtime.block_on(sock.send(GafferPacket::new(peer_addr, vec![1]))).unwrap();
+    assert_eq!(runtime.block_on(peer.recv()).unwrap().payload, vec![1]);
+    // Sends the ack the shutdown is waiting for
+    runtime.block_on(peer.update()).unwrap();
+
+    let report = runtime.block_on(sock.shutdown(Duration::from_secs(5))).unwrap();
+    assert!(report.unconfirmed.is_empty());
+    assert!(sock.peers().is_empty());
+  }
+
+  #[test]
+  fn update_resends_dropped_packets() {
+    let runtime = local_runtime();
+    let _guard = runtime.enter();
+    let mut send_sock = GafferSocket::bind("127.0.0.1:0").unwrap();
+    let mut recv_sock = GafferSocket::bind("127.0.0.1:0").unwrap();
+    let destination = recv_sock.local_addr().unwrap();
+    send_sock.state.requeue_dropped(destination, vec![GafferPacket::new(destination, vec![9])]);
+
+    assert_eq!(runtime.block_on(send_sock.update()).unwrap(), 1);
+    assert_eq!(runtime.block_on(recv_sock.recv()).unwrap().payload, vec![9]);
+  }
+}
@@ -0,0 +1,435 @@
+use std::io;
+
+use std::net::{self, SocketAddr};
+
+use std::ops::Range;
+
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsRawFd;
+
+use mio;
+
+/// Most datagrams handed to the kernel in a single batch call
+pub const MAX_BATCH: usize = 64;
+
+/// Most segments the kernel accepts in one segmentation offload send
+pub const MAX_SEGMENTS: usize = 64;
+
+/// Largest buffer a segmentation offload send or coalesced receive can carry
+pub const MAX_OFFLOAD_BYTES: usize = 65507;
+
+/// The single-datagram operations batching falls back to
+///
+/// Both report `WouldBlock` as an error, like `std::net::UdpSocket`.
+pub trait DatagramSocket {
+  fn send_datagram(&self, buf: &[u8], addr: &SocketAddr) -> io::Result<usize>;
+  fn recv_datagram(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+}
+
+impl DatagramSocket for net::UdpSocket {
+  fn send_datagram(&self, buf: &[u8], addr: &SocketAddr) -> io::Result<usize> {
+    self.send_to(buf, addr)
+  }
+
+  fn recv_datagram(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+    self.recv_from(buf)
+  }
+}
+
+impl DatagramSocket for mio::udp::UdpSocket {
+  fn send_datagram(&self, buf: &[u8], addr: &SocketAddr) -> io::Result<usize> {
+    self.send_to(buf, addr).and_then(|res| res.ok_or_else(would_block))
+  }
+
+  fn recv_datagram(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+    self.recv_from(buf).and_then(|res| res.ok_or_else(would_block))
+  }
+}
+
+fn would_block() -> io::Error {
+  io::Error::new(io::ErrorKind::WouldBlock, "operation would block")
+}
+
+/// Sends datagrams with as few syscalls as the platform allows
+///
+/// Returns how many datagrams were sent, in order. That may be fewer than given when the socket
+/// would block or fails partway; an error is only returned if nothing was sent.
+#[cfg(target_os = "linux")]
+pub fn send_batch<S: DatagramSocket + AsRawFd>(socket: &S, datagrams: &[(SocketAddr, Vec<u8>)]) -> io::Result<usize> {
+  match sys::sendmmsg(socket.as_raw_fd(), datagrams) {
+    Err(ref err) if err.raw_os_error() == Some(::libc::ENOSYS) => send_each(socket, datagrams),
+    res => res,
+  }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn send_batch<S: DatagramSocket>(socket: &S, datagrams: &[(SocketAddr, Vec<u8>)]) -> io::Result<usize> {
+  send_each(socket, datagrams)
+}
+
+/// Receives up to one datagram per buffer
+///
+/// Waits for the first datagram as the socket normally would, then takes whatever else is already
+/// queued. Entry `i` of the result is the length and source of the datagram in `buffers[i]`.
+/// Without `recvmmsg` only one datagram is read per call.
+#[cfg(target_os = "linux")]
+pub fn recv_batch<S: DatagramSocket + AsRawFd>(socket: &S, buffers: &mut [Vec<u8>]) -> io::Result<Vec<(usize, SocketAddr)>> {
+  match sys::recvmmsg(socket.as_raw_fd(), buffers) {
+    Err(ref err) if err.raw_os_error() == Some(::libc::ENOSYS) => recv_one(socket, buffers),
+    res => res,
+  }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn recv_batch<S: DatagramSocket>(socket: &S, buffers: &mut [Vec<u8>]) -> io::Result<Vec<(usize, SocketAddr)>> {
+  recv_one(socket, buffers)
+}
+
+/// Turns UDP segmentation offload (`UDP_SEGMENT` and `UDP_GRO`) on or off for a socket
+///
+/// Returns whether offload is in use afterwards, `false` when the kernel does not support it.
+#[cfg(target_os = "linux")]
+pub fn set_offload<S: AsRawFd>(socket: &S, enabled: bool) -> io::Result<bool> {
+  let fd = socket.as_raw_fd();
+  let res = sys::set_udp_option(fd, sys::UDP_GRO, enabled as ::libc::c_int)
+    // A zero segment size probes for send support without segmenting every send
+    .and_then(|_| sys::set_udp_option(fd, sys::UDP_SEGMENT, 0));
+  match res {
+    Ok(()) => Ok(enabled),
+    Err(ref err) if sys::offload_unsupported(err) => Ok(false),
+    Err(err) => Err(err),
+  }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_offload<S>(_socket: &S, _enabled: bool) -> io::Result<bool> {
+  Ok(false)
+}
+
+/// Sends datagrams, handing runs to a single peer to the kernel as one segmented buffer
+///
+/// Everything that cannot be segmented goes out through `send_batch`. If the kernel does not
+/// support segmentation, `offload` is cleared and the rest is sent as plain datagrams. A run the
+/// device refuses goes out as plain datagrams, leaving `offload` on for later sends. Returns how
+/// many datagrams were sent, like `send_batch`.
+#[cfg(target_os = "linux")]
+pub fn send_offload<S: DatagramSocket + AsRawFd>(socket: &S, datagrams: &[(SocketAddr, Vec<u8>)], offload: &mut bool) -> io::Result<usize> {
+  let mut sent = 0;
+  let mut plain_start = 0;
+  for run in segment_runs(datagrams) {
+    if !*offload || run.len() < 2 {
+      continue;
+    }
+    if !send_plain(socket, &datagrams[plain_start..run.start], &mut sent)? {
+      return Ok(sent);
+    }
+    plain_start = run.start;
+
+    let (addr, first) = &datagrams[run.start];
+    let buffer: Vec<u8> = datagrams[run.clone()].iter().flat_map(|(_, payload)| payload.iter().cloned()).collect();
+    match sys::send_segments(socket.as_raw_fd(), addr, &buffer, first.len()) {
+      Ok(_) => {
+        sent += run.len();
+        plain_start = run.end;
+      },
+      Err(ref err) if sys::offload_unsupported(err) => *offload = false,
+      Err(ref err) if sys::offload_refused(err) => {},
+      Err(err) => return if sent == 0 { Err(err) } else { Ok(sent) },
+    }
+  }
+  send_plain(socket, &datagrams[plain_start..], &mut sent)?;
+  Ok(sent)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn send_offload<S: DatagramSocket>(socket: &S, datagrams: &[(SocketAddr, Vec<u8>)], _offload: &mut bool) -> io::Result<usize> {
+  send_batch(socket, datagrams)
+}
+
+/// Receives one datagram, or several the kernel coalesced
+///
+/// Returns the length, source and segment size. Every segment is `segment_size` bytes except the
+/// last one, which may be shorter.
+#[cfg(target_os = "linux")]
+pub fn recv_segments<S: AsRawFd>(socket: &S, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr, usize)> {
+  sys::recv_segments(socket.as_raw_fd(), buffer)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn recv_segments<S: DatagramSocket>(socket: &S, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr, usize)> {
+  socket.recv_datagram(buffer).map(|(len, addr)| (len, addr, len))
+}
+
+/// Splits datagrams into runs that can share one segmentation offload send
+///
+/// A run goes to a single peer and its datagrams are all the same size, except that the last
+/// one may be shorter, as the kernel requires.
+pub fn segment_runs(datagrams: &[(SocketAddr, Vec<u8>)]) -> Vec<Range<usize>> {
+  let mut runs = Vec::new();
+  let mut start = 0;
+  while start < datagrams.len() {
+    let (addr, first) = &datagrams[start];
+    let size = first.len();
+    let mut bytes = size;
+    let mut end = start + 1;
+    while size > 0 && end < datagrams.len() && end - start < MAX_SEGMENTS {
+      let (next_addr, next) = &datagrams[end];
+      if next_addr != addr || next.len() > size || bytes + next.len() > MAX_OFFLOAD_BYTES {
+        break;
+      }
+      bytes += next.len();
+      end += 1;
+      if next.len() < size {
+        break;
+      }
+    }
+    runs.push(start..end);
+    start = end;
+  }
+  runs
+}
+
+/// Sends a plain batch, adding to `sent`. Returns false if part of it did not go out.
+#[cfg(target_os = "linux")]
+fn send_plain<S: DatagramSocket + AsRawFd>(socket: &S, datagrams: &[(SocketAddr, Vec<u8>)], sent: &mut usize) -> io::Result<bool> {
+  if datagrams.is_empty() {
+    return Ok(true);
+  }
+  match send_batch(socket, datagrams) {
+    Ok(count) => {
+      *sent += count;
+      Ok(count == datagrams.len())
+    },
+    Err(err) => if *sent == 0 { Err(err) } else { Ok(false) },
+  }
+}
+
+fn send_each<S: DatagramSocket>(socket: &S, datagrams: &[(SocketAddr, Vec<u8>)]) -> io::Result<usize> {
+  for (idx, (addr, payload)) in datagrams.iter().enumerate() {
+    if let Err(err) = socket.send_datagram(payload, addr) {
+      return if idx == 0 { Err(err) } else { Ok(idx) };
+    }
+  }
+  Ok(datagrams.len())
+}
+
+fn recv_one<S: DatagramSocket>(socket: &S, buffers: &mut [Vec<u8>]) -> io::Result<Vec<(usize, SocketAddr)>> {
+  match buffers.first_mut() {
+    Some(buffer) => socket.recv_datagram(buffer).map(|res| vec![res]),
+    None => Ok(Vec::new()),
+  }
+}
+
+#[cfg(target_os = "linux")]
+pub mod sys {
+  use libc;
+
+  use std::io;
+  use std::mem;
+  use std::ptr;
+
+  use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+
+  use std::os::unix::io::RawFd;
+
+  use super::MAX_BATCH;
+
+  pub fn sendmmsg(fd: RawFd, datagrams: &[(SocketAddr, Vec<u8>)]) -> io::Result<usize> {
+    let mut sent = 0;
+    for chunk in datagrams.chunks(MAX_BATCH) {
+      let mut names: Vec<(libc::sockaddr_storage, libc::socklen_t)> =
+        chunk.iter().map(|(addr, _)| to_sockaddr(addr)).collect();
+      let mut iovecs: Vec<libc::iovec> = chunk.iter().map(|(_, payload)| {
+        libc::iovec { iov_base: payload.as_ptr() as *mut libc::c_void, iov_len: payload.len() }
+      }).collect();
+      let mut msgs: Vec<libc::mmsghdr> = names.iter_mut().zip(iovecs.iter_mut()).map(|(name, iovec)| {
+        mmsghdr(&mut name.0, name.1, iovec)
+      }).collect();
+
+      let mut offset = 0;
+      while offset < msgs.len() {
+        let res = unsafe {
+          libc::sendmmsg(fd, msgs[offset..].as_mut_ptr(), (msgs.len() - offset) as libc::c_uint, 0)
+        };
+        if res < 0 {
+          let err = io::Error::last_os_error();
+          return if sent == 0 { Err(err) } else { Ok(sent) };
+        }
+        offset += res as usize;
+        sent += res as usize;
+      }
+    }
+    Ok(sent)
+  }
+
+  pub fn recvmmsg(fd: RawFd, buffers: &mut [Vec<u8>]) -> io::Result<Vec<(usize, SocketAddr)>> {
+    let count = buffers.len().min(MAX_BATCH);
+    let mut names: Vec<libc::sockaddr_storage> = (0..count).map(|_| unsafe { mem::zeroed() }).collect();
+    let mut iovecs: Vec<libc::iovec> = buffers[..count].iter_mut().map(|buffer| {
+      libc::iovec { iov_base: buffer.as_mut_ptr() as *mut libc::c_void, iov_len: buffer.len() }
+    }).collect();
+    let mut msgs: Vec<libc::mmsghdr> = names.iter_mut().zip(iovecs.iter_mut()).map(|(name, iovec)| {
+      mmsghdr(name, mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t, iovec)
+    }).collect();
+
+    let res = unsafe {
+      libc::recvmmsg(fd, msgs.as_mut_ptr(), count as libc::c_uint, libc::MSG_WAITFORONE, ptr::null_mut())
+    };
+    if res < 0 {
+      return Err(io::Error::last_os_error());
+    }
+
+    msgs[..res as usize].iter().zip(names.iter()).map(|(msg, name)| {
+      from_sockaddr(name, msg.msg_hdr.msg_namelen).map(|addr| (msg.msg_len as usize, addr))
+    }).collect()
+  }
+
+  fn mmsghdr(name: &mut libc::sockaddr_storage, namelen: libc::socklen_t, iovec: &mut libc::iovec) -> libc::mmsghdr {
+    let mut hdr: libc::msghdr = unsafe { mem::zeroed() };
+    hdr.msg_name = name as *mut libc::sockaddr_storage as *mut libc::c_void;
+    hdr.msg_namelen = namelen;
+    hdr.msg_iov = iovec;
+    hdr.msg_iovlen = 1;
+    libc::mmsghdr { msg_hdr: hdr, msg_len: 0 }
+  }
+
+  pub const UDP_SEGMENT: libc::c_int = 103;
+  pub const UDP_GRO: libc::c_int = 104;
+
+  /// Whether an error means the kernel or socket cannot do segmentation offload at all
+  pub fn offload_unsupported(err: &io::Error) -> bool {
+    matches!(err.raw_os_error(), Some(libc::ENOPROTOOPT) | Some(libc::EOPNOTSUPP))
+  }
+
+  /// Whether an error means this one segmented send was refused, say by a device without
+  /// checksum offload, while later ones may still work
+  pub fn offload_refused(err: &io::Error) -> bool {
+    matches!(err.raw_os_error(), Some(libc::EINVAL) | Some(libc::EIO))
+  }
+
+  pub fn set_udp_option(fd: RawFd, option: libc::c_int, value: libc::c_int) -> io::Result<()> {
+    let res = unsafe {
+      libc::setsockopt(
+        fd,
+        libc::SOL_UDP,
+        option,
+        &value as *const libc::c_int as *const libc::c_void,
+        mem::size_of::<libc::c_int>() as libc::socklen_t)
+    };
+    if res < 0 { Err(io::Error::last_os_error()) } else { Ok(()) }
+  }
+
+  pub fn send_segments(fd: RawFd, addr: &SocketAddr, buffer: &[u8], segment_size: usize) -> io::Result<usize> {
+    let (mut name, namelen) = to_sockaddr(addr);
+    let mut iovec = libc::iovec { iov_base: buffer.as_ptr() as *mut libc::c_void, iov_len: buffer.len() };
+    // u64s keep the control buffer aligned for cmsghdr
+    let mut control = [0u64; 4];
+    let mut hdr = mmsghdr(&mut name, namelen, &mut iovec).msg_hdr;
+    hdr.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    hdr.msg_controllen = unsafe { libc::CMSG_SPACE(mem::size_of::<u16>() as libc::c_uint) } as _;
+    unsafe {
+      let cmsg = libc::CMSG_FIRSTHDR(&hdr);
+      (*cmsg).cmsg_level = libc::SOL_UDP;
+      (*cmsg).cmsg_type = UDP_SEGMENT;
+      (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<u16>() as libc::c_uint) as _;
+      ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut u16, segment_size as u16);
+    }
+
+    let res = unsafe { libc::sendmsg(fd, &hdr, 0) };
+    if res < 0 { Err(io::Error::last_os_error()) } else { Ok(res as usize) }
+  }
+
+  pub fn recv_segments(fd: RawFd, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr, usize)> {
+    let mut name: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut iovec = libc::iovec { iov_base: buffer.as_mut_ptr() as *mut libc::c_void, iov_len: buffer.len() };
+    let mut control = [0u64; 4];
+    let mut hdr = mmsghdr(&mut name, mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t, &mut iovec).msg_hdr;
+    hdr.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    hdr.msg_controllen = mem::size_of_val(&control) as _;
+
+    let res = unsafe { libc::recvmsg(fd, &mut hdr, 0) };
+    if res < 0 {
+      return Err(io::Error::last_os_error());
+    }
+    let len = res as usize;
+
+    let mut segment_size = len;
+    unsafe {
+      let mut cmsg = libc::CMSG_FIRSTHDR(&hdr);
+      while !cmsg.is_null() {
+        if (*cmsg).cmsg_level == libc::SOL_UDP && (*cmsg).cmsg_type == UDP_GRO {
+          segment_size = ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::c_int) as usize;
+        }
+        cmsg = libc::CMSG_NXTHDR(&hdr, cmsg);
+      }
+    }
+    from_sockaddr(&name, hdr.msg_namelen).map(|addr| (len, addr, segment_size.max(1)))
+  }
+
+  pub fn to_sockaddr(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let len = match *addr {
+      SocketAddr::V4(ref addr) => {
+        let sin = unsafe { &mut *(&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in) };
+        sin.sin_family = libc::AF_INET as libc::sa_family_t;
+        sin.sin_port = addr.port().to_be();
+        sin.sin_addr = libc::in_addr { s_addr: u32::from_ne_bytes(addr.ip().octets()) };
+        mem::size_of::<libc::sockaddr_in>()
+      },
+      SocketAddr::V6(ref addr) => {
+        let sin6 = unsafe { &mut *(&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in6) };
+        sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+        sin6.sin6_port = addr.port().to_be();
+        sin6.sin6_flowinfo = addr.flowinfo();
+        sin6.sin6_addr = libc::in6_addr { s6_addr: addr.ip().octets() };
+        sin6.sin6_scope_id = addr.scope_id();
+        mem::size_of::<libc::sockaddr_in6>()
+      },
+    };
+    (storage, len as libc::socklen_t)
+  }
+
+  pub fn from_sockaddr(storage: &libc::sockaddr_storage, len: libc::socklen_t) -> io::Result<SocketAddr> {
+    match storage.ss_family as libc::c_int {
+      libc::AF_INET if len as usize >= mem::size_of::<libc::sockaddr_in>() => {
+        let sin = unsafe { &*(storage as *const libc::sockaddr_storage as *const libc::sockaddr_in) };
+        let ip = Ipv4Addr::from(sin.sin_addr.s_addr.to_ne_bytes());
+        Ok(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(sin.sin_port))))
+      },
+      libc::AF_INET6 if len as usize >= mem::size_of::<libc::sockaddr_in6>() => {
+        let sin6 = unsafe { &*(storage as *const libc::sockaddr_storage as *const libc::sockaddr_in6) };
+        let ip = Ipv6Addr::from(sin6.sin6_addr.s6_addr);
+        Ok(SocketAddr::V6(SocketAddrV6::new(ip, u16::from_be(sin6.sin6_port), sin6.sin6_flowinfo, sin6.sin6_scope_id)))
+      },
+      _ => Err(io::Error::new(io::ErrorKind::InvalidData, "unsupported address family")),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn datagrams(spec: &[(&str, usize)]) -> Vec<(SocketAddr, Vec<u8>)> {
+    spec.iter().map(|&(addr, len)| (addr.parse().unwrap(), vec![0; len])).collect()
+  }
+
+  #[test]
+  fn runs_split_on_peer_and_size() {
+    let datagrams = datagrams(&[
+      ("127.0.0.1:1", 10),
+      ("127.0.0.1:1", 10),
+      ("127.0.0.1:1", 4),
+      ("127.0.0.1:1", 10),
+      ("127.0.0.1:2", 10),
+      ("127.0.0.1:2", 12),
+    ]);
+    assert_eq!(segment_runs(&datagrams), vec![0..3, 3..4, 4..5, 5..6]);
+  }
+
+  #[test]
+  fn runs_respect_segment_limit() {
+    let datagrams = datagrams(&[("127.0.0.1:1", 10); MAX_SEGMENTS + 1]);
+    assert_eq!(segment_runs(&datagrams), vec![0..MAX_SEGMENTS, MAX_SEGMENTS..MAX_SEGMENTS + 1]);
+  }
+}
@@ -1,32 +1,69 @@
 use addr::ToSingleSocketAddr;
 
-use packet::{
-  CompleteGafferPacket,
-  GafferPacket,
-  GAFFER_MTU,
-};
+use config::GafferConfig;
 
-use socket::GafferState;
+use connection::Connection;
+
+use filter::{AddressFilter, IpRange};
+
+#[cfg(feature = "crypto")]
+use crypto::PacketKeys;
+
+use event::GafferEvent;
+
+use packet::{DisconnectReason, GafferPacket};
+
+use socket::{batch, GafferState, PartialSend, ShutdownReport, SHUTDOWN_POLL};
+
+use stats::GafferStats;
+
+#[cfg(feature = "crypto")]
+use token::ConnectToken;
 
 use std::io;
 
-use std::net::UdpSocket;
+use std::collections::VecDeque;
+
+use std::net::{SocketAddr, UdpSocket};
+
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use std::thread;
+
+use std::time::{Duration, Instant};
 
 pub struct GafferSocket {
   udp_socket: UdpSocket,
   state: GafferState,
-  recv_buffer: [u8; GAFFER_MTU],
+  recv_buffer: Vec<u8>,
+  batch_buffers: Vec<Vec<u8>>,
+  gso: bool,
+  gro: bool,
+  offload_buffer: Vec<u8>,
+  coalesced: VecDeque<GafferPacket>,
 }
 
 impl GafferSocket {
   pub fn bind<A: ToSingleSocketAddr>(addr: A) -> io::Result<Self> {
-    let first_addr = addr.to_single_socket_addr().unwrap();
-    UdpSocket::bind(&first_addr).map(|sock| {
-      GafferSocket {
+    GafferSocket::bind_with_config(addr, GafferConfig::new())
+  }
+
+  /// Bind with socket options and protocol settings from `config`
+  pub fn bind_with_config<A: ToSingleSocketAddr>(addr: A, config: GafferConfig) -> io::Result<Self> {
+    let first_addr = addr.to_single_socket_addr()?;
+    config.bind_udp(first_addr).and_then(|sock| {
+      let mut state = GafferState::with_config(config);
+      state.set_local_addr(sock.local_addr()?);
+      Ok(GafferSocket {
         udp_socket: sock,
-        state: GafferState::new(),
-        recv_buffer: [0; GAFFER_MTU]
-      }
+        recv_buffer: vec![0; state.recv_buffer_size()],
+        state,
+        batch_buffers: Vec::new(),
+        gso: false,
+        gro: false,
+        offload_buffer: Vec::new(),
+        coalesced: VecDeque::new(),
+      })
     })
   }
 
@@ -37,38 +74,882 @@ impl GafferSocket {
   /// - Identify dropped packets from message header
   /// - Forget own acked packets
   /// - Enqueue Sure-Dropped packets into resubmit-queue
+  ///
+  /// A datagram larger than the configured MTU is discarded with an `InvalidData` error, and
+  /// counted in `stats`. Path MTU probes are handled here and never returned.
   pub fn recv(&mut self) -> io::Result<GafferPacket> {
-    self.udp_socket.recv_from(&mut self.recv_buffer)
-      // TODO: Fix to_vec, it is suboptimal here
-      .and_then(|(len, addr)| CompleteGafferPacket::deserialize(self.recv_buffer[..len].to_vec()).map(|res| (addr, res)) )
-      .map(|(addr, packet)| self.state.receive(addr, packet))
+    if self.gro {
+      return self.recv_coalesced();
+    }
+    loop {
+      let (len, addr) = self.udp_socket.recv_from(&mut self.recv_buffer)?;
+      let received = self.state.process_datagram(addr, &self.recv_buffer[..len]);
+      send_control(&self.udp_socket, &mut self.state);
+      if let Some(packet) = received? {
+        return Ok(packet);
+      }
+    }
   }
 
   /// Send a normal message
   ///
   /// - Send dropped packets
   /// - Send packet
+  ///
+  /// If a send fails, the packet that failed and any not yet sent are kept for the next send and
+  /// the error carries a `PartialSend`. A payload over the MTU is refused up front with
+  /// `PayloadTooLarge`.
   pub fn send(&mut self, p: GafferPacket) -> io::Result<usize> {
-    let dropped_packets = self.state.dropped_packets(p.addr);
-    for packet in dropped_packets.into_iter() {
-      // TODO: if this fails, a bunch of packets are dropped
-      try!(self.single_send(packet));
+    self.state.check_send(&p)?;
+    let resent = resend_dropped(&self.udp_socket, &mut self.state, p.addr, &mut self.gso)?;
+    send_after_resends(&self.udp_socket, &mut self.state, p, resent)
+  }
+
+  /// Send several packets with as few syscalls as the platform allows
+  ///
+  /// Uses `sendmmsg` on Linux. Dropped packets for the peers involved go out first, as with
+  /// `send`. Returns how many datagrams were sent. If a send fails, whatever did not go out is put
+  /// back in front of its peer's dropped packets and the error is a `PartialSend`.
+  pub fn send_batch(&mut self, packets: Vec<GafferPacket>) -> io::Result<usize> {
+    for p in &packets {
+      self.state.check_send(p)?;
+    }
+    let packets = self.state.with_dropped_packets(packets);
+    let datagrams = self.state.preprocess_batch(packets);
+    let mut sent = batch::send_offload(&self.udp_socket, &datagrams, &mut self.gso).unwrap_or(0);
+    let mut failure = None;
+    for (destination, datagram) in &datagrams[sent..] {
+      match self.udp_socket.send_to(datagram, destination) {
+        Ok(_) => sent += 1,
+        Err(err) => {
+          failure = Some(err);
+          break;
+        },
+      }
+    }
+    let failed = datagrams.get(sent).map(|&(addr, _)| addr);
+    let requeued = datagrams.len() - sent;
+    self.state.requeue_unsent_batch(&datagrams[sent..]);
+    for (_, datagram) in datagrams {
+      self.state.recycle(datagram);
+    }
+    match (failure, failed) {
+      (Some(err), Some(addr)) => Err(PartialSend { addr, resent: sent, requeued, cause: err }.into()),
+      _ => Ok(sent),
     }
-    self.single_send(p)
   }
 
+  /// Receive up to `max` packets with as few syscalls as the platform allows
   ///
-  /// - Get and increment sequence number
-  /// - Remember packet
-  /// - Add all headers
-  ///   - Sequence #
-  ///   - Current ack
-  ///   - Ack bitfield
-  /// - Send packet
-  fn single_send(&mut self, p: GafferPacket) -> io::Result<usize> {
-    let (destination, payload) = self.state.preprocess_packet(p);
+  /// Blocks until at least one datagram arrives, then takes whatever else is queued, using
+  /// `recvmmsg` on Linux. Malformed and truncated datagrams are skipped.
+  pub fn recv_batch(&mut self, max: usize) -> io::Result<Vec<GafferPacket>> {
+    if self.gro {
+      let mut packets = vec![self.recv_coalesced()?];
+      while packets.len() < max {
+        match self.coalesced.pop_front() {
+          Some(packet) => packets.push(packet),
+          None => break,
+        }
+      }
+      return Ok(packets);
+    }
+    if self.batch_buffers.len() < max {
+      self.batch_buffers.resize(max, vec![0; self.state.recv_buffer_size()]);
+    }
+    let received = batch::recv_batch(&self.udp_socket, &mut self.batch_buffers[..max])?;
+    let packets = self.state.receive_batch(&self.batch_buffers, received);
+    send_control(&self.udp_socket, &mut self.state);
+    Ok(packets)
+  }
+
+  /// Turns UDP segmentation offload (GSO and GRO) on or off, on Linux
+  ///
+  /// With offload on, `send_batch` hands runs of equally sized datagrams for one peer, such as
+  /// resends or a large transfer, to the kernel as a single buffer. Received datagrams the kernel
+  /// coalesced are split up again. Returns whether offload is in use, `false` if the kernel does
+  /// not support it; sends also fall back to plain datagrams if the device rejects segmentation.
+  pub fn set_segmentation_offload(&mut self, enabled: bool) -> io::Result<bool> {
+    let enabled = batch::set_offload(&self.udp_socket, enabled)?;
+    self.gso = enabled;
+    self.gro = enabled;
+    self.offload_buffer = if enabled { vec![0; batch::MAX_OFFLOAD_BYTES] } else { Vec::new() };
+    Ok(enabled)
+  }
 
-    self.udp_socket.send_to(payload.as_ref(), &destination)
+  /// Resend dropped packets for every known connection
+  ///
+  /// Meant to be called periodically, so dropped packets go out even when the app has nothing
+  /// new to send to that peer. Also sends path MTU probes when discovery is enabled, connect
+  /// requests and a `Hello` to peers that have not answered yet, and an `Ack` to peers owed one,
+  /// see `GafferState::queue_acks`. Returns the number of packets resent.
+  pub fn update(&mut self) -> io::Result<usize> {
+    update(&self.udp_socket, &mut self.state, &mut self.gso)
+  }
+
+  /// Stops sending and waits up to `timeout` for packets in flight to be confirmed
+  ///
+  /// New sends are refused with `BrokenPipe` from the start. Until every connection has nothing
+  /// waiting for an ack, or `timeout` passes, the socket keeps receiving and resending what is
+  /// dropped. Peers with nothing to send confirm with an `Ack` from their `update`. Every peer
+  /// is then sent a `Disconnect` with `DisconnectReason::Shutdown` and forgotten, waiting for
+  /// all of its copies to go out.
+  ///
+  /// Returns the packets that were never confirmed, see `GafferState::finish_shutdown`, and
+  /// those received meanwhile.
+  pub fn shutdown(&mut self, timeout: Duration) -> io::Result<ShutdownReport> {
+    let deadline = Instant::now() + timeout;
+    let read_timeout = self.udp_socket.read_timeout()?;
+    let mut received = Vec::new();
+    self.state.begin_shutdown();
+    loop {
+      let now = Instant::now();
+      if now >= deadline || !self.state.has_unconfirmed() {
+        break;
+      }
+      self.udp_socket.set_read_timeout(Some((deadline - now).min(SHUTDOWN_POLL)))?;
+      // Errors are for single datagrams or sends, and failed resends are retried next time round
+      if let Ok(packet) = self.recv() {
+        received.push(packet);
+      }
+      let _ = self.update();
+    }
+    self.udp_socket.set_read_timeout(read_timeout)?;
+    let unconfirmed = self.state.finish_shutdown();
+    send_control(&self.udp_socket, &mut self.state);
+    // The later copies of each `Disconnect` are spaced out
+    while let Some(due) = self.state.next_farewell() {
+      thread::sleep(due.saturating_duration_since(Instant::now()));
+      send_control(&self.udp_socket, &mut self.state);
+    }
+    Ok(ShutdownReport { unconfirmed, received })
+  }
+
+  /// The address the socket is bound to, useful after binding to port 0
+  pub fn local_addr(&self) -> io::Result<SocketAddr> {
+    self.udp_socket.local_addr()
+  }
+
+  /// Counters for received traffic that was discarded
+  pub fn stats(&self) -> &GafferStats {
+    self.state.stats()
+  }
+
+  /// Addresses of every peer with a connection
+  pub fn peers(&self) -> Vec<SocketAddr> {
+    self.state.peers()
+  }
+
+  pub fn connection(&self, addr: &SocketAddr) -> Option<&Connection> {
+    self.state.connection(addr)
+  }
+
+  /// Drops all state for a peer, see `GafferState::forget`
+  pub fn forget_peer(&mut self, addr: &SocketAddr) -> Option<Connection> {
+    self.state.forget(addr)
+  }
+
+  /// Tells a peer we are leaving and forgets it, see `GafferState::disconnect`
+  pub fn disconnect(&mut self, addr: SocketAddr, reason: DisconnectReason) -> io::Result<Connection> {
+    let connection = self.state.disconnect(addr, reason)?;
+    send_control(&self.udp_socket, &mut self.state);
+    Ok(connection)
+  }
+
+  /// Which addresses datagrams are accepted from, see `GafferState::filter_mut`
+  pub fn filter_mut(&mut self) -> &mut AddressFilter {
+    self.state.filter_mut()
+  }
+
+  /// Shuts a range out for `duration` and forgets its connections, see `GafferState::ban`
+  pub fn ban(&mut self, range: IpRange, duration: Duration) -> Vec<SocketAddr> {
+    self.state.ban(range, duration)
+  }
+
+  /// Encrypts everything to and from a peer, see `GafferState::set_keys`
+  #[cfg(feature = "crypto")]
+  pub fn set_peer_keys(&mut self, addr: SocketAddr, keys: &PacketKeys) -> io::Result<()> {
+    self.state.set_keys(addr, keys)
+  }
+
+  /// Connects to a server with a token from the backend, see `GafferState::connect`
+  ///
+  /// The request is resent on every `update` until the server answers, which raises a
+  /// `GafferEvent::Connected`.
+  #[cfg(feature = "crypto")]
+  pub fn connect(&mut self, server: SocketAddr, token: &ConnectToken) -> io::Result<()> {
+    self.state.connect(server, token)?;
+    send_connects(&self.udp_socket, &mut self.state)
+  }
+
+  /// Next connection event, such as a client being let in with a connect token
+  pub fn poll_event(&mut self) -> Option<GafferEvent> {
+    self.state.poll_event()
+  }
+
+  /// Largest payload known to reach `addr` in one datagram, see `GafferState::path_max_payload`
+  pub fn path_max_payload(&self, addr: &SocketAddr) -> usize {
+    self.state.path_max_payload(addr)
+  }
+
+  /// Sets the read timeout of the underlying socket, see `UdpSocket::set_read_timeout`
+  pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+    self.udp_socket.set_read_timeout(timeout)
+  }
+
+  /// Splits the socket into halves that can be used from different threads
+  ///
+  /// Both halves share the connection state behind a mutex, which is only held while a packet
+  /// is processed. One thread can block in `GafferReceiver::recv` while others send. The halves
+  /// do not use segmentation offload, so it is turned off.
+  pub fn split(mut self) -> io::Result<(GafferSender, GafferReceiver)> {
+    if self.gro {
+      self.set_segmentation_offload(false)?;
+    }
+    let send_socket = self.udp_socket.try_clone()?;
+    let state = Arc::new(Mutex::new(self.state));
+    let sender = GafferSender {
+      udp_socket: Arc::new(send_socket),
+      state: state.clone(),
+    };
+    let receiver = GafferReceiver {
+      udp_socket: self.udp_socket,
+      state,
+      recv_buffer: self.recv_buffer,
+    };
+    Ok((sender, receiver))
+  }
+
+  fn recv_coalesced(&mut self) -> io::Result<GafferPacket> {
+    loop {
+      if let Some(packet) = self.coalesced.pop_front() {
+        return Ok(packet);
+      }
+      let (len, addr, segment_size) = batch::recv_segments(&self.udp_socket, &mut self.offload_buffer)?;
+      let packets = self.state.receive_segments(&self.offload_buffer[..len], segment_size, addr);
+      send_control(&self.udp_socket, &mut self.state);
+      self.coalesced.extend(packets);
+    }
   }
 }
 
+/// Sending half of a split `GafferSocket`
+///
+/// Cheap to clone, every clone shares the same socket and state.
+#[derive(Clone)]
+pub struct GafferSender {
+  udp_socket: Arc<UdpSocket>,
+  state: Arc<Mutex<GafferState>>,
+}
+
+impl GafferSender {
+  /// Send a normal message, see `GafferSocket::send`
+  pub fn send(&self, p: GafferPacket) -> io::Result<usize> {
+    let mut state = lock(&self.state);
+    state.check_send(&p)?;
+    let resent = resend_dropped(&self.udp_socket, &mut state, p.addr, &mut false)?;
+    send_after_resends(&self.udp_socket, &mut state, p, resent)
+  }
+
+  /// Resend dropped packets for every known connection, see `GafferSocket::update`
+  pub fn update(&self) -> io::Result<usize> {
+    update(&self.udp_socket, &mut lock(&self.state), &mut false)
+  }
+
+  /// Stops sending and waits up to `timeout` for packets in flight to be confirmed, see
+  /// `GafferSocket::shutdown`
+  ///
+  /// Acks only come in through the `GafferReceiver`, so another thread has to keep calling its
+  /// `recv` meanwhile; packets received during the shutdown are returned from there as usual.
+  /// The state is not locked while waiting. Returns the packets that were never confirmed.
+  pub fn shutdown(&self, timeout: Duration) -> Vec<GafferPacket> {
+    let deadline = Instant::now() + timeout;
+    lock(&self.state).begin_shutdown();
+    loop {
+      let now = {
+        let mut state = lock(&self.state);
+        // Failed resends are retried next time round
+        let _ = update(&self.udp_socket, &mut state, &mut false);
+        let now = Instant::now();
+        if now >= deadline || !state.has_unconfirmed() {
+          break;
+        }
+        now
+      };
+      thread::sleep((deadline - now).min(SHUTDOWN_POLL));
+    }
+    let unconfirmed = lock(&self.state).finish_shutdown();
+    loop {
+      let due = {
+        let mut state = lock(&self.state);
+        send_control(&self.udp_socket, &mut state);
+        match state.next_farewell() {
+          Some(due) => due,
+          None => break,
+        }
+      };
+      thread::sleep(due.saturating_duration_since(Instant::now()));
+    }
+    unconfirmed
+  }
+}
+
+/// Receiving half of a split `GafferSocket`
+pub struct GafferReceiver {
+  udp_socket: UdpSocket,
+  state: Arc<Mutex<GafferState>>,
+  recv_buffer: Vec<u8>,
+}
+
+impl GafferReceiver {
+  /// Receive a normal message, see `GafferSocket::recv`
+  ///
+  /// The state is only locked once a datagram has arrived.
+  pub fn recv(&mut self) -> io::Result<GafferPacket> {
+    loop {
+      let (len, addr) = self.udp_socket.recv_from(&mut self.recv_buffer)?;
+      let mut state = lock(&self.state);
+      let received = state.process_datagram(addr, &self.recv_buffer[..len]);
+      send_control(&self.udp_socket, &mut state);
+      if let Some(packet) = received? {
+        return Ok(packet);
+      }
+    }
+  }
+
+  /// Sets the read timeout of the underlying socket, see `UdpSocket::set_read_timeout`
+  pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+    self.udp_socket.set_read_timeout(timeout)
+  }
+
+  /// Next connection event, see `GafferSocket::poll_event`
+  pub fn poll_event(&self) -> Option<GafferEvent> {
+    lock(&self.state).poll_event()
+  }
+}
+
+/// Locks shared state, a panic on another thread holding the lock does not corrupt it
+fn lock(state: &Mutex<GafferState>) -> MutexGuard<'_, GafferState> {
+  state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn update(udp_socket: &UdpSocket, state: &mut GafferState, gso: &mut bool) -> io::Result<usize> {
+  let mut resent = 0;
+  for addr in state.addrs_with_dropped_packets() {
+    resent += resend_dropped(udp_socket, state, addr, gso)?;
+  }
+  for (addr, probe) in state.preprocess_probes() {
+    let result = udp_socket.send_to(&probe, addr);
+    state.probe_sent(addr, &result);
+    state.recycle(probe);
+  }
+  send_connects(udp_socket, state)?;
+  state.queue_hellos();
+  state.queue_acks();
+  send_control(udp_socket, state);
+  Ok(resent)
+}
+
+/// Sends the datagrams the protocol queued by itself
+///
+/// Failures are ignored, a lost challenge or response only costs the peer a retry.
+fn send_control(udp_socket: &UdpSocket, state: &mut GafferState) {
+  while let Some((addr, datagram)) = state.poll_control() {
+    let _ = udp_socket.send_to(&datagram, addr);
+    state.recycle(datagram);
+  }
+}
+
+fn send_connects(udp_socket: &UdpSocket, state: &mut GafferState) -> io::Result<()> {
+  for (addr, datagram) in state.preprocess_connects() {
+    let result = udp_socket.send_to(&datagram, addr);
+    state.recycle(datagram);
+    if let Err(err) = result {
+      return Err(PartialSend { addr, resent: 0, requeued: 0, cause: err }.into());
+    }
+  }
+  Ok(())
+}
+
+/// Resends the dropped packets for `addr` as one batch
+///
+/// A short batch does not say why it stopped, so whatever is left goes out one datagram at a
+/// time until a send fails.
+fn resend_dropped(udp_socket: &UdpSocket, state: &mut GafferState, addr: SocketAddr, gso: &mut bool) -> io::Result<usize> {
+  let packets = state.dropped_packets(addr);
+  if packets.is_empty() {
+    return Ok(0);
+  }
+  let count = packets.len();
+  let datagrams = state.preprocess_batch(packets);
+  let mut resent = batch::send_offload(udp_socket, &datagrams, gso).unwrap_or(0);
+  let mut failure = None;
+  for (destination, datagram) in &datagrams[resent..] {
+    match udp_socket.send_to(datagram, destination) {
+      Ok(_) => resent += 1,
+      Err(err) => {
+        failure = Some(err);
+        break;
+      },
+    }
+  }
+  for (_, datagram) in datagrams {
+    state.recycle(datagram);
+  }
+  match failure {
+    Some(err) => {
+      let requeued = count - resent;
+      state.requeue_unsent(addr, requeued);
+      Err(PartialSend { addr, resent, requeued, cause: err }.into())
+    },
+    None => Ok(resent),
+  }
+}
+
+/// Sends a new packet once `resent` dropped ones went out, requeueing it if the send fails
+fn send_after_resends(udp_socket: &UdpSocket, state: &mut GafferState, p: GafferPacket, resent: usize) -> io::Result<usize> {
+  let addr = p.addr;
+  single_send(udp_socket, state, p).map_err(|err| {
+    state.requeue_unsent(addr, 1);
+    PartialSend { addr, resent, requeued: 1, cause: err }.into()
+  })
+}
+
+///
+/// - Get and increment sequence number
+/// - Remember packet
+/// - Add all headers
+///   - Sequence #
+///   - Current ack
+///   - Ack bitfield
+/// - Send packet
+fn single_send(udp_socket: &UdpSocket, state: &mut GafferState, p: GafferPacket) -> io::Result<usize> {
+  let (destination, payload) = state.preprocess_packet(p);
+
+  let res = udp_socket.send_to(payload.as_ref(), destination);
+  state.recycle(payload);
+  res
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use connection::PROBE_RESOLUTION;
+  use packet::{GafferPacket, GAFFER_HEADER_SIZE};
+  use pool::SMALL_BUFFER_SIZE;
+
+  use std::thread;
+
+  #[test]
+  fn split_halves_work_across_threads() {
+    let (sender, mut receiver) = GafferSocket::bind("127.0.0.1:45230").unwrap().split().unwrap();
+    let mut peer = GafferSocket::bind("127.0.0.1:45231").unwrap();
+
+    let waiting = thread::spawn(move || receiver.recv().unwrap());
+
+    // The receive half is blocked, sending must not wait on it
+    sender.clone().send(GafferPacket::new("127.0.0.1:45231", vec![1])).unwrap();
+    assert_eq!(peer.recv().unwrap().payload, vec![1]);
+
+    peer.send(GafferPacket::new("127.0.0.1:45230", vec![2])).unwrap();
+    assert_eq!(waiting.join().unwrap().payload, vec![2]);
+  }
+
+  #[test]
+  fn batches_packets() {
+    let mut send_sock = GafferSocket::bind("127.0.0.1:45232").unwrap();
+    let mut recv_sock = GafferSocket::bind("127.0.0.1:45233").unwrap();
+
+    let packets = (0..3).map(|idx| GafferPacket::new("127.0.0.1:45233", vec![idx])).collect();
+    assert_eq!(send_sock.send_batch(packets).unwrap(), 3);
+
+    let received = recv_sock.recv_batch(8).unwrap();
+    let payloads: Vec<Vec<u8>> = received.into_iter().map(|p| p.payload.into_vec()).collect();
+    if cfg!(target_os = "linux") {
+      assert_eq!(payloads, vec![vec![0], vec![1], vec![2]]);
+    } else {
+      assert_eq!(payloads, vec![vec![0]]);
+    }
+  }
+
+  #[test]
+  fn failed_batches_are_requeued() {
+    let mut sock = GafferSocket::bind("127.0.0.1:0").unwrap();
+    // Not routable from a loopback-bound socket, so every send fails
+    let destination = "198.51.100.1:45221".parse().unwrap();
+    sock.state.requeue_dropped(destination, vec![GafferPacket::new(destination, vec![9])]);
+
+    let packets = (0..2).map(|idx| GafferPacket::new(destination, vec![idx])).collect();
+    let err = sock.send_batch(packets).unwrap_err();
+    {
+      let partial = err.get_ref().unwrap().downcast_ref::<PartialSend>().unwrap();
+      assert_eq!(partial.resent, 0);
+      assert_eq!(partial.requeued, 3);
+    }
+    let payloads: Vec<Vec<u8>> = sock.state.dropped_packets(destination).into_iter().map(|p| p.payload.into_vec()).collect();
+    assert_eq!(payloads, vec![vec![9], vec![0], vec![1]]);
+  }
+
+  #[test]
+  fn update_resends_dropped_packets() {
+    let mut send_sock = GafferSocket::bind("127.0.0.1:0").unwrap();
+    let mut recv_sock = GafferSocket::bind("127.0.0.1:0").unwrap();
+    let destination = recv_sock.local_addr().unwrap();
+    let dropped = (0..3).map(|idx| GafferPacket::new(destination, vec![idx])).collect();
+    send_sock.state.requeue_dropped(destination, dropped);
+
+    assert_eq!(send_sock.update().unwrap(), 3);
+    recv_sock.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    let payloads: Vec<Vec<u8>> = (0..3).map(|_| recv_sock.recv().unwrap().payload.into_vec()).collect();
+    assert_eq!(payloads, vec![vec![0], vec![1], vec![2]]);
+  }
+
+  #[test]
+  fn offload_round_trips_or_falls_back() {
+    let mut send_sock = GafferSocket::bind("127.0.0.1:45235").unwrap();
+    let mut recv_sock = GafferSocket::bind("127.0.0.1:45236").unwrap();
+    let send_offload = send_sock.set_segmentation_offload(true).unwrap();
+    let recv_offload = recv_sock.set_segmentation_offload(true).unwrap();
+    assert_eq!(send_offload, recv_offload);
+
+    let mut packets: Vec<GafferPacket> = (0..8).map(|idx| GafferPacket::new("127.0.0.1:45236", vec![idx; 100])).collect();
+    packets.push(GafferPacket::new("127.0.0.1:45236", vec![8; 10]));
+    assert_eq!(send_sock.send_batch(packets).unwrap(), 9);
+
+    let mut payloads = Vec::new();
+    while payloads.len() < 9 {
+      payloads.extend(recv_sock.recv_batch(16).unwrap().into_iter().map(|p| p.payload));
+    }
+    let mut expected: Vec<Vec<u8>> = (0..8).map(|idx| vec![idx; 100]).collect();
+    expected.push(vec![8; 10]);
+    assert_eq!(payloads, expected);
+  }
+
+  #[test]
+  fn binds_with_config() {
+    let mut config = GafferConfig::new();
+    config.recv_buffer_size = Some(1 << 20);
+    config.ttl = Some(7);
+    config.dscp = Some(46);
+    config.reuse_address = true;
+    let sock = GafferSocket::bind_with_config("127.0.0.1:45237", config).unwrap();
+
+    assert_eq!(sock.udp_socket.ttl().unwrap(), 7);
+    let raw = socket2::SockRef::from(&sock.udp_socket);
+    assert!(raw.recv_buffer_size().unwrap() >= 1 << 20);
+    assert!(raw.reuse_address().unwrap());
+    assert_eq!(raw.tos().unwrap(), 46 << 2);
+  }
+
+  #[test]
+  fn rejects_out_of_range_dscp() {
+    let mut config = GafferConfig::new();
+    config.dscp = Some(64);
+    assert!(GafferSocket::bind_with_config("127.0.0.1:45238", config).is_err());
+  }
+
+  #[test]
+  fn rejects_rate_limits_below_a_datagram() {
+    use limit::RateLimit;
+
+    let mut config = GafferConfig::new();
+    config.global_rate_limit = Some(RateLimit::new(100, config.mtu as u32 - 1));
+    let err = GafferSocket::bind_with_config("127.0.0.1:0", config.clone()).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+    config.global_rate_limit = Some(RateLimit::new(0, 1 << 20));
+    assert!(GafferSocket::bind_with_config("127.0.0.1:0", config).is_err());
+  }
+
+  #[test]
+  fn discovers_path_mtu() {
+    let mut config = GafferConfig::new();
+    config.path_mtu_discovery = true;
+    // Past the largest udp payload, so the top of the search is refused by the OS
+    config.path_mtu_max = 70000;
+    let mut sock = GafferSocket::bind_with_config("127.0.0.1:0", config.clone()).unwrap();
+    let mut peer = GafferSocket::bind_with_config("127.0.0.1:0", config).unwrap();
+    sock.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    peer.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    let sock_addr = sock.local_addr().unwrap();
+    let peer_addr = peer.local_addr().unwrap();
+
+    sock.send(GafferPacket::new(peer_addr, vec![0])).unwrap();
+    for _ in 0..64 {
+      sock.update().unwrap();
+      sock.send(GafferPacket::new(peer_addr, vec![1])).unwrap();
+      // Probes are consumed by the peer, only data comes out of recv
+      while peer.recv().unwrap().payload != vec![1] {}
+      peer.send(GafferPacket::new(sock_addr, vec![2])).unwrap();
+      assert_eq!(sock.recv().unwrap().payload, vec![2]);
+    }
+
+    let max_payload = 65507 - GAFFER_HEADER_SIZE;
+    let discovered = sock.path_max_payload(&peer_addr);
+    assert!(discovered <= max_payload);
+    assert!(discovered + PROBE_RESOLUTION > max_payload, "settled on {}", discovered);
+    assert!(sock.connection(&peer_addr).unwrap().dropped_packets.is_empty());
+  }
+
+  #[test]
+  fn recycles_buffers() {
+    let mut sock = GafferSocket::bind("127.0.0.1:0").unwrap();
+    let mut peer = GafferSocket::bind("127.0.0.1:0").unwrap();
+    let peer_addr = peer.local_addr().unwrap();
+
+    sock.send(GafferPacket::new(peer_addr, vec![1, 2, 3])).unwrap();
+    assert_eq!(sock.state.pool().idle(), 1);
+
+    // Small payloads are decoded into small buffers
+    let packet = peer.recv().unwrap();
+    assert_eq!(peer.state.small_pool().idle(), 0);
+    drop(packet);
+    assert_eq!(peer.state.small_pool().idle(), 1);
+    assert_eq!(peer.state.pool().idle(), 0);
+
+    sock.send(GafferPacket::new(peer_addr, vec![5; 1000])).unwrap();
+    let packet = peer.recv().unwrap();
+    assert!(packet.payload.len() > SMALL_BUFFER_SIZE);
+    drop(packet);
+    assert_eq!(peer.state.pool().idle(), 1);
+
+    sock.send(GafferPacket::new(peer_addr, vec![4])).unwrap();
+    assert_eq!(sock.state.pool().idle(), 1);
+    assert_eq!(sock.state.small_pool().idle(), 0);
+  }
+
+  #[cfg(feature = "crypto")]
+  #[test]
+  fn admits_clients_with_connect_tokens() {
+    use crypto::PacketKeys;
+    use event::GafferEvent;
+    use token::TokenGenerator;
+
+    let mut config = GafferConfig::new();
+    config.connect_token_key = Some([9; 32]);
+    let mut server = GafferSocket::bind_with_config("127.0.0.1:0", config).unwrap();
+    let mut client = GafferSocket::bind("127.0.0.1:0").unwrap();
+    let server_addr = server.local_addr().unwrap();
+    let client_addr = client.local_addr().unwrap();
+    server.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+
+    // A token signed with another key gets nowhere
+    let keys = PacketKeys::new([1; 32], [2; 32]);
+    let forged = TokenGenerator::new(&[8; 32]).generate(6, &[server_addr], Duration::from_secs(30), keys.clone()).unwrap();
+    client.connect(server_addr, &forged).unwrap();
+    assert!(server.recv().is_err());
+    assert!(server.connection(&client_addr).is_none());
+    assert_eq!(server.stats().unauthenticated, 1);
+    assert_eq!(server.poll_event(), None);
+
+    let mut generator = TokenGenerator::new(&[9; 32]);
+    let token = generator.generate(7, &[server_addr], Duration::from_secs(30), keys).unwrap();
+    client.connect(server_addr, &token).unwrap();
+    // Repeats before the server answers keep the same keys and session
+    client.connect(server_addr, &token).unwrap();
+    assert!(server.recv().is_err());
+    assert_eq!(server.poll_event(), Some(GafferEvent::Connected(client_addr, 7)));
+
+    server.send(GafferPacket::new(client_addr, vec![1, 2, 3])).unwrap();
+    assert_eq!(client.recv().unwrap().payload, vec![1, 2, 3]);
+    assert_eq!(client.poll_event(), Some(GafferEvent::Connected(server_addr, 7)));
+    client.send(GafferPacket::new(server_addr, vec![4])).unwrap();
+    assert_eq!(server.recv().unwrap().payload, vec![4]);
+    assert_eq!(client.connect(server_addr, &token).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+
+    // The same token from another address is refused
+    let mut thief = GafferSocket::bind("127.0.0.1:0").unwrap();
+    thief.connect(server_addr, &token).unwrap();
+    assert_eq!(server.recv().unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+    assert!(server.connection(&thief.local_addr().unwrap()).is_none());
+
+    // And so is the same client once its connection is gone
+    server.forget_peer(&client_addr);
+    client.forget_peer(&server_addr);
+    client.connect(server_addr, &token).unwrap();
+    assert_eq!(server.recv().unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+    assert!(server.connection(&client_addr).is_none());
+  }
+
+  #[cfg(feature = "crypto")]
+  #[test]
+  fn challenges_new_addresses() {
+    use packet::{CompleteGafferPacket, PacketKind};
+
+    let mut config = GafferConfig::new();
+    config.cookie_key = Some([5; 32]);
+    let mut server = GafferSocket::bind_with_config("127.0.0.1:0", config).unwrap();
+    let mut client = GafferSocket::bind("127.0.0.1:0").unwrap();
+    let server_addr = server.local_addr().unwrap();
+    let client_addr = client.local_addr().unwrap();
+    server.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+    client.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+
+    // The first packet only earns a challenge no larger than itself
+    client.send(GafferPacket::new(server_addr, vec![5; 40])).unwrap();
+    assert!(server.recv().is_err());
+    assert_eq!(server.stats().challenged, 1);
+    assert!(server.connection(&client_addr).is_none());
+
+    // Answering it queues the packet again
+    assert!(client.recv().is_err());
+    assert_eq!(client.connection(&server_addr).unwrap().dropped_packets.len(), 1);
+    assert!(server.recv().is_err());
+    assert!(server.connection(&client_addr).is_some());
+    client.update().unwrap();
+    assert_eq!(server.recv().unwrap().payload, vec![5; 40]);
+
+    // Once the server has answered, challenges are forgeries
+    server.send(GafferPacket::new(client_addr, vec![6])).unwrap();
+    assert_eq!(client.recv().unwrap().payload, vec![6]);
+    let challenge = CompleteGafferPacket { kind: PacketKind::Challenge, seq: 0, ack_seq: 0, ack_field: 0, message_id: 0, payload: vec![0; 24].into() };
+    client.send(GafferPacket::new(server_addr, vec![7])).unwrap();
+    assert!(client.state.process_datagram(server_addr, &challenge.serialized()).unwrap().is_none());
+    assert!(client.connection(&server_addr).unwrap().dropped_packets.is_empty());
+    assert!(client.state.poll_control().is_none());
+    assert_eq!(server.recv().unwrap().payload, vec![7]);
+
+    let stranger = ::std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let tiny = CompleteGafferPacket { kind: PacketKind::Data, seq: 0, ack_seq: 0, ack_field: 0, message_id: 0, payload: vec![1].into() };
+    stranger.send_to(&tiny.serialized(), server_addr).unwrap();
+    assert!(server.recv().is_err());
+    assert_eq!(server.stats().unchallenged, 1);
+
+    // A client whose packets are all too small to challenge gets in with a padded hello
+    let mut small = GafferSocket::bind("127.0.0.1:0").unwrap();
+    small.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+    small.send(GafferPacket::new(server_addr, vec![8])).unwrap();
+    assert!(server.recv().is_err());
+    assert_eq!(server.stats().unchallenged, 2);
+    small.update().unwrap();
+    assert!(server.recv().is_err());
+    assert_eq!(server.stats().challenged, 2);
+    assert!(small.recv().is_err());
+    assert!(server.recv().is_err());
+    small.update().unwrap();
+    assert_eq!(server.recv().unwrap().payload, vec![8]);
+
+    let forged = CompleteGafferPacket { kind: PacketKind::Response, seq: 0, ack_seq: 0, ack_field: 0, message_id: 0, payload: vec![0; 24].into() };
+    stranger.send_to(&forged.serialized(), server_addr).unwrap();
+    assert_eq!(server.recv().unwrap_err().kind(), io::ErrorKind::InvalidData);
+    assert_eq!(server.stats().bad_cookies, 1);
+    assert!(server.connection(&stranger.local_addr().unwrap()).is_none());
+  }
+
+  #[test]
+  fn bans_peers() {
+    let mut sock = GafferSocket::bind("127.0.0.1:0").unwrap();
+    let mut peer = GafferSocket::bind("127.0.0.1:0").unwrap();
+    let sock_addr = sock.local_addr().unwrap();
+    let peer_addr = peer.local_addr().unwrap();
+    sock.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+
+    peer.send(GafferPacket::new(sock_addr, vec![1])).unwrap();
+    assert_eq!(sock.recv().unwrap().payload, vec![1]);
+
+    let banned = sock.ban(IpRange::from(peer_addr.ip()), Duration::from_secs(60));
+    assert_eq!(banned, vec![peer_addr]);
+    assert!(sock.connection(&peer_addr).is_none());
+    peer.send(GafferPacket::new(sock_addr, vec![2])).unwrap();
+    assert!(sock.recv().is_err());
+    assert_eq!(sock.stats().filtered, 1);
+    assert!(sock.connection(&peer_addr).is_none());
+
+    sock.filter_mut().unban(&IpRange::from(peer_addr.ip()));
+    peer.send(GafferPacket::new(sock_addr, vec![3])).unwrap();
+    assert_eq!(sock.recv().unwrap().payload, vec![3]);
+  }
+
+  #[test]
+  fn shutdown_waits_for_acks() {
+    let mut sock = GafferSocket::bind("127.0.0.1:0").unwrap();
+    let mut peer = GafferSocket::bind("127.0.0.1:0").unwrap();
+    let sock_addr = sock.local_addr().unwrap();
+    let peer_addr = peer.local_addr().unwrap();
+    peer.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+
+    sock.send(GafferPacket::new(peer_addr, vec![1])).unwrap();
+    sock.send(GafferPacket::new(peer_addr, vec![2])).unwrap();
+    assert_eq!(peer.recv().unwrap().payload, vec![1]);
+    assert_eq!(peer.recv().unwrap().payload, vec![2]);
+    // Carries the acks for both
+    peer.send(GafferPacket::new(sock_addr, vec![3])).unwrap();
+
+    let started = Instant::now();
+    let report = sock.shutdown(Duration::from_secs(5)).unwrap();
+    assert!(report.unconfirmed.is_empty());
+    assert_eq!(report.received, vec![GafferPacket::new(peer_addr, vec![3])]);
+    assert!(started.elapsed() < Duration::from_secs(5));
+    assert!(sock.peers().is_empty());
+    assert_eq!(sock.send(GafferPacket::new(peer_addr, vec![4])).unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+
+    assert!(peer.recv().is_err());
+    assert_eq!(peer.poll_event(), Some(GafferEvent::Disconnected(sock_addr, DisconnectReason::Shutdown)));
+    assert!(peer.connection(&sock_addr).is_none());
+  }
+
+  #[test]
+  fn shutdown_reports_unconfirmed_packets() {
+    let mut sock = GafferSocket::bind("127.0.0.1:0").unwrap();
+    let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let silent_addr = silent.local_addr().unwrap();
+
+    sock.send(GafferPacket::new(silent_addr, vec![1])).unwrap();
+    sock.send(GafferPacket::new(silent_addr, vec![2])).unwrap();
+
+    let report = sock.shutdown(Duration::from_millis(50)).unwrap();
+    let payloads: Vec<Vec<u8>> = report.unconfirmed.into_iter().map(|p| p.payload.into_vec()).collect();
+    assert_eq!(payloads, vec![vec![1], vec![2]]);
+    assert!(sock.peers().is_empty());
+  }
+
+  #[test]
+  fn idle_peers_ack_on_update() {
+    let mut sock = GafferSocket::bind("127.0.0.1:0").unwrap();
+    let mut peer = GafferSocket::bind("127.0.0.1:0").unwrap();
+    let peer_addr = peer.local_addr().unwrap();
+    peer.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+
+    sock.send(GafferPacket::new(peer_addr, vec![1])).unwrap();
+    assert_eq!(peer.recv().unwrap().payload, vec![1]);
+    // Nothing to send back, the ack goes out on its own
+    let handle = thread::spawn(move || {
+      peer.update().unwrap();
+      peer
+    });
+
+    let started = Instant::now();
+    let report = sock.shutdown(Duration::from_secs(5)).unwrap();
+    assert!(report.unconfirmed.is_empty());
+    assert!(report.received.is_empty());
+    assert!(started.elapsed() < Duration::from_secs(5));
+    let peer = handle.join().unwrap();
+    assert!(!peer.connection(&sock.local_addr().unwrap()).unwrap().owes_ack);
+  }
+
+  #[test]
+  fn split_sender_shuts_down() {
+    let sock = GafferSocket::bind("127.0.0.1:0").unwrap();
+    let mut peer = GafferSocket::bind("127.0.0.1:0").unwrap();
+    let sock_addr = sock.local_addr().unwrap();
+    let peer_addr = peer.local_addr().unwrap();
+    peer.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+    let (sender, mut receiver) = sock.split().unwrap();
+    receiver.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+
+    sender.send(GafferPacket::new(peer_addr, vec![1])).unwrap();
+    assert_eq!(peer.recv().unwrap().payload, vec![1]);
+    peer.send(GafferPacket::new(sock_addr, vec![2])).unwrap();
+
+    let receiving = thread::spawn(move || {
+      let mut received = Vec::new();
+      while let Ok(packet) = receiver.recv() {
+        received.push(packet.payload.into_vec());
+      }
+      received
+    });
+    assert!(sender.shutdown(Duration::from_secs(5)).is_empty());
+    assert_eq!(receiving.join().unwrap(), vec![vec![2]]);
+    assert_eq!(sender.send(GafferPacket::new(peer_addr, vec![3])).unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+  }
+}
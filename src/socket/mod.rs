@@ -1,64 +1,1204 @@
 use packet::{
   CompleteGafferPacket,
-  GafferPacket
+  DisconnectReason,
+  GafferPacket,
+  GafferPayload,
+  PacketKind,
+  GAFFER_HEADER_SIZE,
+  HELLO_PADDING,
 };
 
-use connection::Connection;
+use config::{FullPolicy, GafferConfig};
+
+use connection::{Connection, PathMtu, RESYNC_PACKETS};
+#[cfg(feature = "crypto")]
+use connection::ExternalAcks;
+
+use replay::ReplayWindow;
+
+use pool::{BufferPool, SMALL_BUFFER_SIZE};
+
+use filter::{AddressFilter, IpRange};
+
+use limit::{Limited, RateLimiter};
+
+#[cfg(feature = "crypto")]
+use cookie::{Cookies, COOKIE_SIZE};
+
+#[cfg(feature = "crypto")]
+use crypto::{PacketCipher, PacketKeys, Rejected, Session, GAFFER_SESSION_SIZE, GAFFER_TAG_SIZE};
+
+use packet::read_header;
+
+use event::GafferEvent;
+
+use stats::GafferStats;
+
+#[cfg(feature = "crypto")]
+use token::{self, ConnectToken};
+
+use std::error::Error;
+
+use std::fmt;
+
+use std::io;
 
 use std::net::SocketAddr;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 
+use std::time::{Duration, Instant};
 
+#[cfg(feature = "crypto")]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Copies of a `Disconnect` packet sent, so one getting through is likely despite loss
+pub const DISCONNECT_COPIES: usize = 3;
+
+/// Time between the copies of a `Disconnect` packet, so a burst of loss does not take them all
+pub const DISCONNECT_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Longest a socket waits for acks between resends while shutting down
+const SHUTDOWN_POLL: Duration = Duration::from_millis(10);
+
+pub mod batch;
 pub mod blocking;
 pub mod non_blocking;
+pub mod threaded;
+#[cfg(feature = "async")]
+pub mod asynchronous;
 
 pub struct GafferState {
-  connections: HashMap<SocketAddr, Connection>
+  connections: HashMap<SocketAddr, Connection>,
+  config: GafferConfig,
+  stats: GafferStats,
+  pool: BufferPool,
+  small_pool: BufferPool,
+  filter: AddressFilter,
+  limiter: RateLimiter,
+  local_addr: Option<SocketAddr>,
+  shutting_down: bool,
+  events: VecDeque<GafferEvent>,
+  /// Datagrams the protocol sends by itself, such as challenges and their responses
+  control: VecDeque<(SocketAddr, Vec<u8>)>,
+  /// Later copies of `Disconnect` packets, held back until they are due
+  farewells: VecDeque<(Instant, SocketAddr, Vec<u8>)>,
+  #[cfg(feature = "crypto")]
+  cookies: Option<Cookies>,
+  /// Nonces of accepted connect tokens, with their expiry
+  #[cfg(feature = "crypto")]
+  used_tokens: HashMap<[u8; 12], u64>,
 }
 
 impl GafferState {
   pub fn new() -> GafferState {
-    GafferState { connections: HashMap::new() }
+    GafferState::with_config(GafferConfig::new())
+  }
+
+  pub fn with_config(config: GafferConfig) -> GafferState {
+    let pool = BufferPool::new(config.buffer_pool_size, config.max_datagram_size());
+    let small_pool = BufferPool::new(config.buffer_pool_size, SMALL_BUFFER_SIZE);
+    let limiter = RateLimiter::new(config.peer_rate_limit, config.global_rate_limit, config.max_rate_limited_peers);
+    #[cfg(feature = "crypto")]
+    let cookies = config.cookie_key.as_ref().map(Cookies::new);
+    GafferState {
+      connections: HashMap::new(),
+      config,
+      stats: GafferStats::default(),
+      pool,
+      small_pool,
+      filter: AddressFilter::new(),
+      limiter,
+      local_addr: None,
+      shutting_down: false,
+      events: VecDeque::new(),
+      control: VecDeque::new(),
+      farewells: VecDeque::new(),
+      #[cfg(feature = "crypto")]
+      cookies,
+      #[cfg(feature = "crypto")]
+      used_tokens: HashMap::new(),
+    }
+  }
+
+  pub fn config(&self) -> &GafferConfig {
+    &self.config
+  }
+
+  pub fn stats(&self) -> &GafferStats {
+    &self.stats
+  }
+
+  /// Buffers datagrams are encoded into and larger received payloads are decoded into
+  pub fn pool(&self) -> &BufferPool {
+    &self.pool
+  }
+
+  /// Buffers received payloads of up to `SMALL_BUFFER_SIZE` bytes are decoded into
+  pub fn small_pool(&self) -> &BufferPool {
+    &self.small_pool
+  }
+
+  /// The address the socket is bound to, connect tokens have to name it
+  pub fn set_local_addr(&mut self, addr: SocketAddr) {
+    self.local_addr = Some(addr);
+  }
+
+  pub fn local_addr(&self) -> Option<SocketAddr> {
+    self.local_addr
+  }
+
+  /// Next thing that happened to a connection, such as a client being let in
+  pub fn poll_event(&mut self) -> Option<GafferEvent> {
+    self.events.pop_front()
+  }
+
+  /// Next datagram the protocol wants sent by itself, such as an answer to a challenge
+  ///
+  /// Sockets send these after every receive and `update`. Hand each one to `recycle` once sent.
+  pub fn poll_control(&mut self) -> Option<(SocketAddr, Vec<u8>)> {
+    if let Some(control) = self.control.pop_front() {
+      return Some(control);
+    }
+    match self.farewells.front() {
+      Some(&(due, _, _)) if due <= Instant::now() => self.farewells.pop_front().map(|(_, addr, datagram)| (addr, datagram)),
+      _ => None,
+    }
+  }
+
+  /// When the next copy of a `Disconnect` packet held back by `disconnect` is due
+  pub fn next_farewell(&self) -> Option<Instant> {
+    self.farewells.front().map(|&(due, _, _)| due)
+  }
+
+  /// Hands an encoded datagram back once it has been sent
+  pub fn recycle(&self, datagram: Vec<u8>) {
+    self.pool.give(datagram)
+  }
+
+  /// Size receive buffers need: one byte over the largest expected datagram, so an oversized
+  /// one fills the whole buffer and can be told apart from one that fit
+  pub fn recv_buffer_size(&self) -> usize {
+    self.config.max_datagram_size() + 1
+  }
+
+  /// Counts and rejects a datagram that did not fit the receive buffer
+  pub fn check_truncation(&mut self, len: usize) -> io::Result<()> {
+    if len > self.config.max_datagram_size() {
+      self.stats.truncated += 1;
+      return Err(io::Error::new(io::ErrorKind::InvalidData, "datagram was larger than the receive buffer"));
+    }
+    Ok(())
+  }
+
+  /// Largest payload that fits in the configured MTU along with the header
+  pub fn max_payload_size(&self) -> usize {
+    self.config.mtu.saturating_sub(GAFFER_HEADER_SIZE)
+  }
+
+  /// Rejects a packet that cannot go out as a single datagram
+  ///
+  /// Checked before a send touches any connection state, so a refused packet never uses up a
+  /// sequence number. Payloads up to the configured MTU are always allowed, larger ones only
+  /// once path MTU discovery has shown they reach the peer. With encryption required, peers
+  /// without keys are refused with `PermissionDenied`. Once shutting down, everything is
+  /// refused with `BrokenPipe`.
+  pub fn check_send(&self, p: &GafferPacket) -> io::Result<()> {
+    if self.shutting_down {
+      return Err(io::Error::new(io::ErrorKind::BrokenPipe, "socket is shutting down"));
+    }
+    #[cfg(feature = "crypto")]
+    {
+      if self.encryption_required() && !self.has_keys(&p.addr) {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "no keys for peer"));
+      }
+    }
+    let max = self.config.mtu.max(self.path_mtu(&p.addr)).saturating_sub(self.overhead(&p.addr));
+    if p.payload.len() > max {
+      return Err(PayloadTooLarge { len: p.payload.len(), max }.into());
+    }
+    Ok(())
+  }
+
+  /// Largest payload known to reach `addr` in one datagram
+  ///
+  /// The result of path MTU discovery when it is enabled, which may be over or under
+  /// `max_payload_size`. Without discovery, or for an unknown peer, `max_payload_size`. Less
+  /// the session header and authentication tag for an encrypted connection.
+  pub fn path_max_payload(&self, addr: &SocketAddr) -> usize {
+    self.path_mtu(addr).saturating_sub(self.overhead(addr))
+  }
+
+  /// Installs keys for a peer, everything to and from it is encrypted from then on
+  ///
+  /// Keys are part of the connection, so `forget` removes them too. A peer that already has
+  /// keys is refused with `AlreadyExists`; forget it first to start over with a new session.
+  #[cfg(feature = "crypto")]
+  pub fn set_keys(&mut self, addr: SocketAddr, keys: &PacketKeys) -> io::Result<()> {
+    let connection = self.connection_entry(addr);
+    if connection.cipher.is_some() {
+      return Err(io::Error::new(io::ErrorKind::AlreadyExists, "peer already has keys"));
+    }
+    connection.cipher = Some(PacketCipher::new(keys)?);
+    Ok(())
+  }
+
+  /// Starts connecting to `server` with a token issued by the backend
+  ///
+  /// Installs the token's keys for the server. Its private part goes out in a `Connect` packet
+  /// from `preprocess_connects` until the server first answers. Connecting again with the same
+  /// token while that goes on changes nothing; a server we already have keys for is refused with
+  /// `AlreadyExists`.
+  #[cfg(feature = "crypto")]
+  pub fn connect(&mut self, server: SocketAddr, token: &ConnectToken) -> io::Result<()> {
+    if !token.server_addrs.contains(&server) {
+      return Err(io::Error::new(io::ErrorKind::InvalidInput, "connect token does not name the server"));
+    }
+    if let Some(connection) = self.connections.get(&server) {
+      match connection.pending_connect {
+        Some(ref pending) if **pending == token.private[..] => return Ok(()),
+        None if connection.cipher.is_some() => {
+          return Err(io::Error::new(io::ErrorKind::AlreadyExists, "already connected to the server"));
+        },
+        _ => {},
+      }
+    }
+    let cipher = PacketCipher::new(&token.keys)?;
+    let connection = self.connection_entry(server);
+    connection.cipher = Some(cipher);
+    connection.client_id = Some(token.client_id);
+    connection.pending_connect = Some(token.private.clone().into());
+    Ok(())
+  }
+
+  /// Builds a `Connect` datagram for every server that has not answered yet
+  ///
+  /// These take no sequence number and are never encrypted, the token inside already is.
+  #[cfg(feature = "crypto")]
+  pub fn preprocess_connects(&mut self) -> Vec<(SocketAddr, Vec<u8>)> {
+    let pool = &self.pool;
+    self.connections.iter().filter_map(|(addr, connection)| {
+      let connect = CompleteGafferPacket {
+        kind: PacketKind::Connect,
+        seq: 0,
+        ack_seq: 0,
+        ack_field: 0,
+        message_id: 0,
+        payload: connection.pending_connect.clone()?,
+      };
+      let mut datagram = pool.take();
+      connect.serialize_into(&mut datagram);
+      Some((*addr, datagram))
+    }).collect()
+  }
+
+  #[cfg(not(feature = "crypto"))]
+  pub fn preprocess_connects(&mut self) -> Vec<(SocketAddr, Vec<u8>)> {
+    Vec::new()
+  }
+
+  /// Queues a padded `Hello` for every peer we are sending to that has not answered yet
+  ///
+  /// The sockets' `update` sends them with the other control datagrams. A server that
+  /// challenges new addresses answers one even when every packet we send it is too small to be
+  /// challenged; once it replies, resends go out from `update` as usual.
+  pub fn queue_hellos(&mut self) {
+    let pool = &self.pool;
+    let control = &mut self.control;
+    for (addr, connection) in &self.connections {
+      if connection.received.newest().is_some() || connection.waiting_packets.is_empty() {
+        continue;
+      }
+      let hello = CompleteGafferPacket {
+        kind: PacketKind::Hello,
+        seq: 0,
+        ack_seq: 0,
+        ack_field: 0,
+        message_id: 0,
+        payload: vec![0; HELLO_PADDING].into(),
+      };
+      let mut datagram = pool.take();
+      hello.serialize_into(&mut datagram);
+      control.push_back((*addr, datagram));
+    }
+  }
+
+  /// Queues an `Ack` for every peer that sent us packets we have not sent anything back since
+  ///
+  /// The sockets' `update` sends them with the other control datagrams, so a peer that only
+  /// ever sends, or is waiting for acks to shut down, hears back without the app sending
+  /// anything. Each takes a sequence number but is never resent.
+  pub fn queue_acks(&mut self) {
+    let pool = &self.pool;
+    let control = &mut self.control;
+    for (addr, connection) in self.connections.iter_mut().filter(|(_, connection)| connection.owes_ack) {
+      let seq = connection.seq_num;
+      connection.seq_num = seq.wrapping_add(1);
+      connection.owes_ack = false;
+      let ack = CompleteGafferPacket {
+        kind: PacketKind::Ack,
+        seq,
+        ack_seq: connection.their_acks.last_seq,
+        ack_field: connection.their_acks.field,
+        message_id: 0,
+        payload: GafferPayload::new(),
+      };
+      let mut datagram = pool.take();
+      ack.serialize_into(&mut datagram);
+      seal(connection, seq, &mut datagram);
+      control.push_back((*addr, datagram));
+    }
+  }
+
+  /// Peers without keys are refused, either by config or because clients need connect tokens
+  #[cfg(feature = "crypto")]
+  fn encryption_required(&self) -> bool {
+    self.config.require_encryption || self.config.connect_token_key.is_some()
+  }
+
+  /// Refuses datagrams from peers without keys while encryption is required, see `decode`
+  #[cfg(feature = "crypto")]
+  fn check_keys(&mut self, addr: &SocketAddr) -> io::Result<()> {
+    if self.encryption_required() && !self.has_keys(addr) {
+      self.stats.unauthenticated += 1;
+      return Err(Rejected::Unauthenticated.into());
+    }
+    Ok(())
+  }
+
+  #[cfg(not(feature = "crypto"))]
+  fn check_keys(&mut self, _addr: &SocketAddr) -> io::Result<()> {
+    Ok(())
+  }
+
+  #[cfg(feature = "crypto")]
+  fn has_keys(&self, addr: &SocketAddr) -> bool {
+    self.connections.get(addr).is_some_and(|connection| connection.cipher.is_some())
+  }
+
+  fn path_mtu(&self, addr: &SocketAddr) -> usize {
+    match self.connections.get(addr) {
+      Some(connection) if self.config.path_mtu_discovery => connection.path_mtu.confirmed,
+      _ => self.config.mtu,
+    }
+  }
+
+  /// Bytes of every datagram to `addr` that are not payload
+  fn overhead(&self, addr: &SocketAddr) -> usize {
+    self.connections.get(addr).map_or(GAFFER_HEADER_SIZE, overhead)
+  }
+
+  /// Addresses of every peer with a connection
+  pub fn peers(&self) -> Vec<SocketAddr> {
+    self.connections.keys().cloned().collect()
+  }
+
+  pub fn connection(&self, addr: &SocketAddr) -> Option<&Connection> {
+    self.connections.get(addr)
+  }
+
+  /// Which addresses datagrams are accepted from
+  pub fn filter(&self) -> &AddressFilter {
+    &self.filter
+  }
+
+  /// Changes to the filter apply to the next datagram received
+  pub fn filter_mut(&mut self) -> &mut AddressFilter {
+    &mut self.filter
+  }
+
+  /// Shuts a range out for `duration` and forgets every connection in it
+  ///
+  /// Returns the addresses of the connections that were dropped.
+  pub fn ban(&mut self, range: IpRange, duration: Duration) -> Vec<SocketAddr> {
+    self.filter.ban(range, duration);
+    let banned: Vec<SocketAddr> = self.connections.keys().filter(|addr| range.contains(&addr.ip())).cloned().collect();
+    for addr in &banned {
+      self.connections.remove(addr);
+    }
+    banned
+  }
+
+  /// Drops all state for a peer, including packets still waiting for an ack
+  ///
+  /// A later packet from or to the address starts a fresh connection.
+  pub fn forget(&mut self, addr: &SocketAddr) -> Option<Connection> {
+    self.connections.remove(addr)
+  }
+
+  /// Tells a peer we are leaving and forgets its connection
+  ///
+  /// Queues `DISCONNECT_COPIES` copies of a `Disconnect` packet for `poll_control`, each with
+  /// its own sequence number. Only the first is ready at once, the others come out of
+  /// `poll_control` `DISCONNECT_INTERVAL` apart, so they go out on later updates; see
+  /// `next_farewell`. Returns the forgotten connection, with anything still waiting for an ack,
+  /// or `NotConnected` if there was none.
+  pub fn disconnect(&mut self, addr: SocketAddr, reason: DisconnectReason) -> io::Result<Connection> {
+    let mut connection = match self.connections.remove(&addr) {
+      Some(connection) => connection,
+      None => return Err(io::Error::new(io::ErrorKind::NotConnected, "no connection to peer")),
+    };
+    let now = Instant::now();
+    for copy in 0..DISCONNECT_COPIES {
+      let seq = connection.seq_num;
+      connection.seq_num = seq.wrapping_add(1);
+      let disconnect = CompleteGafferPacket {
+        kind: PacketKind::Disconnect,
+        seq,
+        ack_seq: connection.their_acks.last_seq,
+        ack_field: connection.their_acks.field,
+        message_id: 0,
+        payload: reason.to_bytes().into(),
+      };
+      let mut datagram = self.pool.take();
+      disconnect.serialize_into(&mut datagram);
+      seal(&mut connection, seq, &mut datagram);
+      match copy {
+        0 => self.control.push_back((addr, datagram)),
+        _ => self.farewells.push_back((now + DISCONNECT_INTERVAL * copy as u32, addr, datagram)),
+      }
+    }
+    Ok(connection)
+  }
+
+  /// Stops new sends and path MTU probes, so only packets already in flight are left
+  ///
+  /// A probe waiting for an ack is given up on, the shutdown does not wait for it.
+  pub fn begin_shutdown(&mut self) {
+    self.shutting_down = true;
+    for connection in self.connections.values_mut() {
+      if let Some(seq) = connection.path_mtu.probe_seq() {
+        connection.waiting_packets.remove(seq);
+        connection.path_mtu.probe_cancelled();
+      }
+    }
+  }
+
+  pub fn is_shutting_down(&self) -> bool {
+    self.shutting_down
+  }
+
+  /// Whether any connection still has packets waiting for an ack or to be resent
+  pub fn has_unconfirmed(&self) -> bool {
+    self.connections.values().any(|connection| {
+      !connection.waiting_packets.is_empty() || !connection.dropped_packets.is_empty()
+    })
+  }
+
+  /// Disconnects every peer with `DisconnectReason::Shutdown`, see `disconnect`
+  ///
+  /// Returns the packets that were never confirmed: for each peer, those waiting to be resent
+  /// and then those waiting for an ack, oldest first.
+  pub fn finish_shutdown(&mut self) -> Vec<GafferPacket> {
+    self.begin_shutdown();
+    let mut unconfirmed = Vec::new();
+    for addr in self.peers() {
+      if let Ok(mut connection) = self.disconnect(addr, DisconnectReason::Shutdown) {
+        let waiting = connection.waiting_packets.drain(connection.seq_num);
+        unconfirmed.append(&mut connection.dropped_packets);
+        unconfirmed.extend(waiting.into_iter().map(|(_, p)| p));
+      }
+    }
+    unconfirmed
   }
 
   pub fn preprocess_packet(&mut self, p: GafferPacket) -> (SocketAddr, Vec<u8>) {
-    let connection = self.connections.entry(p.addr).or_insert(Connection::new());
-    connection.waiting_packets.enqueue(connection.seq_num, p.clone());
-    let final_packet = helpers::assemble_packet(connection.seq_num, p.clone(), connection);
-    connection.seq_num = connection.seq_num.wrapping_add(1);
-    (p.addr, final_packet.serialized())
+    let mut datagram = self.pool.take();
+    let connection = self.connection_entry(p.addr);
+    let seq = connection.seq_num;
+    // Resends keep the id they were first sent with
+    let p = match p.message_id {
+      Some(_) => p,
+      None => {
+        let message_id = connection.next_message_id;
+        connection.next_message_id = message_id.wrapping_add(1);
+        GafferPacket { message_id: Some(message_id), ..p }
+      },
+    };
+    connection.waiting_packets.enqueue(seq, p.clone());
+    let final_packet = helpers::assemble_packet(seq, p.clone(), connection);
+    connection.seq_num = seq.wrapping_add(1);
+    connection.owes_ack = false;
+    final_packet.serialize_into(&mut datagram);
+    seal(connection, seq, &mut datagram);
+    (p.addr, datagram)
+  }
+
+  /// Prepares several packets at once, see `preprocess_packet`
+  pub fn preprocess_batch(&mut self, packets: Vec<GafferPacket>) -> Vec<(SocketAddr, Vec<u8>)> {
+    packets.into_iter().map(|p| self.preprocess_packet(p)).collect()
+  }
+
+  /// Puts the dropped packets of every peer in `packets` ahead of them, ready for a batch send
+  pub fn with_dropped_packets(&mut self, packets: Vec<GafferPacket>) -> Vec<GafferPacket> {
+    let mut addrs: Vec<SocketAddr> = packets.iter().map(|p| p.addr).collect();
+    addrs.sort();
+    addrs.dedup();
+    let mut batch: Vec<GafferPacket> = addrs.into_iter().flat_map(|addr| self.dropped_packets(addr)).collect();
+    batch.extend(packets);
+    batch
   }
 
   pub fn dropped_packets(&mut self, addr: SocketAddr) -> Vec<GafferPacket> {
-    let connection = self.connections.entry(addr).or_insert(Connection::new());
+    let connection = self.connection_entry(addr);
     connection.dropped_packets.drain(..).collect()
   }
 
-  fn receive(&mut self, addr: SocketAddr, packet: CompleteGafferPacket) -> GafferPacket {
-    let connection = self.connections.entry(addr).or_insert(Connection::new());
+  /// Addresses of connections that have dropped packets waiting to be resent
+  pub fn addrs_with_dropped_packets(&self) -> Vec<SocketAddr> {
+    self.connections.iter()
+      .filter(|&(_, connection)| !connection.dropped_packets.is_empty())
+      .map(|(addr, _)| *addr)
+      .collect()
+  }
+
+  /// Puts dropped packets that could not be resent back in front of the connection's queue
+  pub fn requeue_dropped(&mut self, addr: SocketAddr, mut packets: Vec<GafferPacket>) {
+    let connection = self.connection_entry(addr);
+    packets.append(&mut connection.dropped_packets);
+    connection.dropped_packets = packets;
+  }
+
+  /// Takes the last `count` packets sent to `addr` back off the ack record, for sends that failed
+  ///
+  /// They go in front of the dropped packets, oldest first, so they are retried before anything
+  /// else. Their sequence numbers stay used.
+  pub fn requeue_unsent(&mut self, addr: SocketAddr, count: usize) {
+    let connection = match self.connections.get_mut(&addr) {
+      Some(connection) => connection,
+      None => return,
+    };
+    let seq_num = connection.seq_num;
+    let mut unsent: Vec<GafferPacket> = (1..=count).rev()
+      .filter_map(|back| connection.waiting_packets.remove(seq_num.wrapping_sub(back as u16)))
+      .collect();
+    unsent.append(&mut connection.dropped_packets);
+    connection.dropped_packets = unsent;
+  }
+
+  /// Requeues the tail of a batch that did not go out, like `requeue_unsent` for every peer in it
+  ///
+  /// A batch holds each peer's packets in sequence order, so whatever is left of it is always the
+  /// last few packets sent to each of those peers.
+  pub fn requeue_unsent_batch(&mut self, unsent: &[(SocketAddr, Vec<u8>)]) {
+    let mut counts: Vec<(SocketAddr, usize)> = Vec::new();
+    for &(addr, _) in unsent {
+      match counts.iter_mut().find(|&&mut (counted, _)| counted == addr) {
+        Some(&mut (_, ref mut count)) => *count += 1,
+        None => counts.push((addr, 1)),
+      }
+    }
+    for (addr, count) in counts {
+      self.requeue_unsent(addr, count);
+    }
+  }
+
+  /// Builds a path MTU probe for every connection that is due one
+  ///
+  /// Each probe takes a sequence number and waits for an ack like a normal packet, but is never
+  /// resent. Pass the result of sending each one to `probe_sent`.
+  pub fn preprocess_probes(&mut self) -> Vec<(SocketAddr, Vec<u8>)> {
+    if !self.config.path_mtu_discovery || self.shutting_down {
+      return Vec::new();
+    }
+    let pool = &self.pool;
+    self.connections.iter_mut().filter_map(|(addr, connection)| {
+      let size = connection.path_mtu.next_probe()?;
+      let seq = connection.seq_num;
+      connection.waiting_packets.enqueue(seq, GafferPacket::new(*addr, GafferPayload::new()));
+      connection.path_mtu.probing(seq, size);
+      connection.seq_num = seq.wrapping_add(1);
+      connection.owes_ack = false;
+      let probe = CompleteGafferPacket {
+        kind: PacketKind::Probe,
+        seq,
+        ack_seq: connection.their_acks.last_seq,
+        ack_field: connection.their_acks.field,
+        message_id: 0,
+        payload: vec![0; size.saturating_sub(overhead(connection))].into(),
+      };
+      let mut datagram = pool.take();
+      probe.serialize_into(&mut datagram);
+      seal(connection, seq, &mut datagram);
+      Some((*addr, datagram))
+    }).collect()
+  }
+
+  /// Records how sending a probe from `preprocess_probes` went
+  ///
+  /// A send the OS refused outright, such as with EMSGSIZE, counts as a failed probe, see
+  /// `PathMtu::probe_failed`. One that would have blocked or was interrupted is cancelled and
+  /// tried again later.
+  pub fn probe_sent(&mut self, addr: SocketAddr, result: &io::Result<usize>) {
+    let connection = match self.connections.get_mut(&addr) {
+      Some(connection) => connection,
+      None => return,
+    };
+    let err = match *result {
+      Ok(_) => return,
+      Err(ref err) => err,
+    };
+    if let Some(seq) = connection.path_mtu.probe_seq() {
+      connection.waiting_packets.remove(seq);
+    }
+    match err.kind() {
+      io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted => connection.path_mtu.probe_cancelled(),
+      _ => connection.path_mtu.probe_failed(),
+    }
+  }
+
+  /// Runs a received datagram through the state
+  ///
+  /// Truncated and malformed datagrams are errors. Returns `Ok(None)` for datagrams the protocol
+  /// consumes itself, such as path MTU probes, connect requests and challenges.
+  ///
+  /// Datagrams the filter shuts out or that go over a rate limit are dropped first, before
+  /// anything else is looked at, and also give `Ok(None)`. With a cookie key configured,
+  /// datagrams from addresses without a `Connection` are answered with a challenge and dropped,
+  /// see `screen`.
+  pub fn process_datagram(&mut self, addr: SocketAddr, datagram: &[u8]) -> io::Result<Option<GafferPacket>> {
+    let now = Instant::now();
+    if !self.filter.permits(&addr.ip(), now) {
+      self.stats.filtered += 1;
+      return Ok(None);
+    }
+    if let Err(limited) = self.limiter.admit(addr, datagram.len(), now) {
+      self.stats.rate_limited += 1;
+      if limited == (Limited::Peer { first: true }) && self.config.report_abuse {
+        self.events.push_back(GafferEvent::PeerAbusive(addr));
+      }
+      return Ok(None);
+    }
+    self.check_truncation(datagram.len())?;
+    let kind = read_header(datagram)?.0;
+    if !self.screen(addr, kind, datagram)? {
+      return Ok(None);
+    }
+    match kind {
+      PacketKind::Connect => return self.admit(addr, &datagram[GAFFER_HEADER_SIZE..]).map(|()| None),
+      PacketKind::Challenge => {
+        self.answer_challenge(addr, &datagram[GAFFER_HEADER_SIZE..]);
+        return Ok(None);
+      },
+      // Already let in, the cookie was a duplicate
+      PacketKind::Response | PacketKind::Hello => return Ok(None),
+      PacketKind::Full => {
+        self.turned_away(addr);
+        return Ok(None);
+      },
+      // The redundant copies arriving after the first one tore the connection down, and acks
+      // for a connection that is gone
+      PacketKind::Disconnect | PacketKind::Ack if !self.connections.contains_key(&addr) => return Ok(None),
+      PacketKind::Data | PacketKind::Probe | PacketKind::Disconnect | PacketKind::Ack => {},
+    }
+    // Room is made before decoding, so refused datagrams are never copied out
+    self.check_keys(&addr)?;
+    if !self.make_room(addr, false) {
+      return Ok(None);
+    }
+    let (packet, extended) = self.decode(addr, datagram)?;
+    self.check_sequence(addr, extended)?;
+    if packet.kind == PacketKind::Disconnect {
+      let reason = DisconnectReason::from_bytes(&packet.payload)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "unknown disconnect reason"))?;
+      self.connections.remove(&addr);
+      self.events.push_back(GafferEvent::Disconnected(addr, reason));
+      return Ok(None);
+    }
+    Ok(self.receive(addr, packet))
+  }
+
+  /// Runs received datagrams through `process_datagram`, skipping ones that were truncated or do
+  /// not deserialize
+  pub(crate) fn receive_batch(&mut self, buffers: &[Vec<u8>], received: Vec<(usize, SocketAddr)>) -> Vec<GafferPacket> {
+    received.into_iter().zip(buffers.iter()).filter_map(|((len, addr), buffer)| {
+      self.process_datagram(addr, &buffer[..len]).ok()?
+    }).collect()
+  }
+
+  /// Runs datagrams the kernel coalesced through `process_datagram`, skipping ones that do not
+  /// deserialize
+  pub(crate) fn receive_segments(&mut self, buffer: &[u8], segment_size: usize, addr: SocketAddr) -> Vec<GafferPacket> {
+    buffer.chunks(segment_size).filter_map(|segment| {
+      self.process_datagram(addr, segment).ok()?
+    }).collect()
+  }
+
+  /// Rejects a sequence number already received, too old to tell, or too far ahead
+  ///
+  /// Runs before the packet touches any ack state, so replays are never delivered twice and a
+  /// forged jump cannot wipe the acks. A peer that really did lose more than
+  /// `GafferConfig::max_sequence_jump` packets keeps sending past the gap, so `RESYNC_PACKETS`
+  /// datagrams in a row that far ahead, each following on from the last, are taken as the new
+  /// position. `extended` is the sequence number as `decode` extended it.
+  fn check_sequence(&mut self, addr: SocketAddr, extended: u64) -> io::Result<()> {
+    let max_jump = self.config.max_sequence_jump as u64;
+    let connection = self.connection_entry(addr);
+    let window = &mut connection.received;
+    if window.newest().is_some_and(|newest| extended > newest + max_jump) {
+      let run = match connection.ahead {
+        Some((last, run)) if extended > last && extended <= last + max_jump => run + 1,
+        _ => 1,
+      };
+      if run < RESYNC_PACKETS {
+        connection.ahead = Some((extended, run));
+        self.stats.out_of_window += 1;
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "sequence number is too far ahead"));
+      }
+    }
+    connection.ahead = None;
+    if !window.check(extended) {
+      self.stats.replayed += 1;
+      return Err(io::Error::new(io::ErrorKind::InvalidData, "sequence number was already received"));
+    }
+    window.accept(extended);
+    Ok(())
+  }
+
+  /// Makes new addresses prove they receive what is sent to them before any state is kept
+  ///
+  /// Anything from an address without a `Connection` is answered with a cookie bound to the
+  /// address and the time, then dropped. Only a `Response` echoing the cookie creates the
+  /// `Connection`, so spoofed source addresses cannot fill the table. A challenge is never
+  /// larger than the datagram it answers, smaller ones go unanswered; clients send a padded
+  /// `Hello` for that, see `queue_hellos`. Returns whether the datagram should be processed
+  /// further.
+  #[cfg(feature = "crypto")]
+  fn screen(&mut self, addr: SocketAddr, kind: PacketKind, datagram: &[u8]) -> io::Result<bool> {
+    let cookies = match self.cookies {
+      Some(ref cookies) if !self.connections.contains_key(&addr) => cookies,
+      _ => return Ok(true),
+    };
+    match kind {
+      PacketKind::Response => {
+        if !cookies.check(&addr, &datagram[GAFFER_HEADER_SIZE..], unix_now()) {
+          self.stats.bad_cookies += 1;
+          return Err(io::Error::new(io::ErrorKind::InvalidData, "challenge cookie is not valid"));
+        }
+        if self.make_room(addr, true) {
+          self.connection_entry(addr);
+        }
+      },
+      // Only ever answered for addresses we already talk to
+      PacketKind::Challenge | PacketKind::Full | PacketKind::Disconnect | PacketKind::Ack => {},
+      _ if datagram.len() < GAFFER_HEADER_SIZE + COOKIE_SIZE => self.stats.unchallenged += 1,
+      _ => {
+        let challenge = CompleteGafferPacket {
+          kind: PacketKind::Challenge,
+          seq: 0,
+          ack_seq: 0,
+          ack_field: 0,
+          message_id: 0,
+          payload: cookies.bake(&addr, unix_now()).to_vec().into(),
+        };
+        let mut reply = self.pool.take();
+        challenge.serialize_into(&mut reply);
+        self.control.push_back((addr, reply));
+        self.stats.challenged += 1;
+      },
+    }
+    Ok(false)
+  }
+
+  #[cfg(not(feature = "crypto"))]
+  fn screen(&mut self, _addr: SocketAddr, _kind: PacketKind, _datagram: &[u8]) -> io::Result<bool> {
+    Ok(true)
+  }
+
+  /// Whether a new `Connection` for `addr` fits under `GafferConfig::max_connections`
+  ///
+  /// Once the limit is reached the new peer is either sent a `Full` reply, a bare header that
+  /// is never larger than what it sent, or the connection that has gone longest without
+  /// receiving anything is forgotten in its place. Only a `proven` peer, one that echoed a
+  /// challenge cookie or holds a connect token, evicts anyone; a spoofed source address could
+  /// otherwise push out every real peer. Peers that already have a connection always fit.
+  fn make_room(&mut self, addr: SocketAddr, proven: bool) -> bool {
+    let max = match self.config.max_connections {
+      Some(max) if !self.connections.contains_key(&addr) && self.connections.len() >= max => max,
+      _ => return true,
+    };
+    match self.config.when_full {
+      FullPolicy::EvictLeastRecent if max > 0 && proven => {
+        let evicted = self.connections.iter()
+          .min_by_key(|&(_, connection)| connection.last_activity)
+          .map(|(addr, _)| *addr);
+        if let Some(evicted) = evicted {
+          self.connections.remove(&evicted);
+          self.stats.evicted += 1;
+          self.events.push_back(GafferEvent::PeerEvicted(evicted));
+        }
+        true
+      },
+      _ => {
+        let full = CompleteGafferPacket {
+          kind: PacketKind::Full,
+          seq: 0,
+          ack_seq: 0,
+          ack_field: 0,
+          message_id: 0,
+          payload: GafferPayload::new(),
+        };
+        let mut reply = self.pool.take();
+        full.serialize_into(&mut reply);
+        self.control.push_back((addr, reply));
+        self.stats.rejected_full += 1;
+        self.events.push_back(GafferEvent::PeerRejected(addr));
+        false
+      },
+    }
+  }
+
+  /// Gives up on a server that replied it is full
+  ///
+  /// Only heeded while nothing has been received from it yet, so a forged reply cannot cut off
+  /// an established connection.
+  fn turned_away(&mut self, addr: SocketAddr) {
+    let waiting = self.connections.get(&addr).is_some_and(|connection| connection.received.newest().is_none());
+    if waiting {
+      self.connections.remove(&addr);
+      self.events.push_back(GafferEvent::ServerFull(addr));
+    }
+  }
+
+  /// Echoes a peer's cookie back, and resends what it dropped while it did not know us
+  ///
+  /// Challenges are not authenticated, so they are only heeded from addresses we sent something
+  /// to and have not heard from yet. A forged one cannot make an established connection resend
+  /// everything in flight.
+  fn answer_challenge(&mut self, addr: SocketAddr, cookie: &[u8]) {
+    let connection = match self.connections.get_mut(&addr) {
+      Some(connection) if connection.received.newest().is_none() => connection,
+      _ => return,
+    };
+    let probe = connection.path_mtu.probe_seq();
+    if probe.is_some() {
+      connection.path_mtu.probe_cancelled();
+    }
+    let unanswered = connection.waiting_packets.drain(connection.seq_num);
+    connection.dropped_packets.extend(unanswered.into_iter()
+      .filter(|&(seq, _)| Some(seq) != probe)
+      .map(|(_, p)| p));
+
+    let response = CompleteGafferPacket {
+      kind: PacketKind::Response,
+      seq: 0,
+      ack_seq: 0,
+      ack_field: 0,
+      message_id: 0,
+      payload: cookie.into(),
+    };
+    let mut datagram = self.pool.take();
+    response.serialize_into(&mut datagram);
+    self.control.push_back((addr, datagram));
+  }
+
+  /// Lets a client in with the private part of its connect token
+  ///
+  /// The token must be valid, name this server and not have been used before, not even by the
+  /// same address once its connection is gone; the client needs a fresh token to come back.
+  /// Nothing is sent back, so a forged request has no reply to amplify. Repeats from a client
+  /// that is already in are ignored.
+  #[cfg(feature = "crypto")]
+  fn admit(&mut self, addr: SocketAddr, private: &[u8]) -> io::Result<()> {
+    let key = match self.config.connect_token_key {
+      Some(key) => key,
+      None => {
+        self.stats.unauthenticated += 1;
+        return Err(Rejected::Unauthenticated.into());
+      },
+    };
+    if self.connections.get(&addr).is_some_and(|connection| connection.client_id.is_some()) {
+      return Ok(());
+    }
+    let token = match token::open_token(&key, private, SystemTime::now()) {
+      Ok(token) => token,
+      Err(err) => {
+        self.stats.unauthenticated += 1;
+        return Err(err);
+      },
+    };
+
+    let now = unix_now();
+    self.used_tokens.retain(|_, &mut expires_at| expires_at > now);
+    let refusal = if self.local_addr.is_some_and(|local| !token.names_server(&local)) {
+      Some("connect token is for another server")
+    } else if self.used_tokens.contains_key(&token.nonce) {
+      Some("connect token was already used")
+    } else {
+      None
+    };
+    if let Some(refusal) = refusal {
+      self.stats.unauthenticated += 1;
+      return Err(io::Error::new(io::ErrorKind::PermissionDenied, refusal));
+    }
+
+    let cipher = PacketCipher::new(&token.keys)?;
+    if !self.make_room(addr, true) {
+      return Ok(());
+    }
+    self.used_tokens.insert(token.nonce, token.expires_at);
+    let connection = self.connection_entry(addr);
+    connection.cipher = Some(cipher);
+    connection.client_id = Some(token.client_id);
+    self.events.push_back(GafferEvent::Connected(addr, token.client_id));
+    Ok(())
+  }
+
+  #[cfg(not(feature = "crypto"))]
+  fn admit(&mut self, _addr: SocketAddr, _private: &[u8]) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::InvalidData, "connect tokens need the crypto feature"))
+  }
+
+  /// Deserializes a datagram, along with its sequence number extended past the 16 header bits
+  #[cfg(not(feature = "crypto"))]
+  fn decode(&mut self, addr: SocketAddr, datagram: &[u8]) -> io::Result<(CompleteGafferPacket, u64)> {
+    let pool = payload_pool(&self.pool, &self.small_pool, datagram.len().saturating_sub(GAFFER_HEADER_SIZE));
+    let packet = CompleteGafferPacket::deserialize_pooled(datagram, pool)?;
+    let extended = self.extend_seq(&addr, packet.seq);
+    Ok((packet, extended))
+  }
+
+  /// Extends a sequence number from `addr` relative to the newest one received from it
+  fn extend_seq(&self, addr: &SocketAddr, seq: u16) -> u64 {
+    self.connections.get(addr).map_or(seq as u64, |connection| connection.received.extend(seq))
+  }
+
+  /// Decrypts datagrams from peers with keys
+  ///
+  /// With encryption required, datagrams from anyone else are refused before a `Connection` is
+  /// created for them. The first one from a server we sent a connect token to completes the
+  /// connection. One that starts a new session of the peer, say after it restarted, clears what
+  /// was recorded of its old one. Encrypted datagrams carry the extended sequence number in their
+  /// session header, unencrypted ones have it extended like without the `crypto` feature.
+  #[cfg(feature = "crypto")]
+  fn decode(&mut self, addr: SocketAddr, datagram: &[u8]) -> io::Result<(CompleteGafferPacket, u64)> {
+    let required = self.encryption_required();
+    let connection = match self.connections.get_mut(&addr) {
+      Some(connection) if connection.cipher.is_some() => connection,
+      _ if required => {
+        self.stats.unauthenticated += 1;
+        return Err(Rejected::Unauthenticated.into());
+      },
+      _ => {
+        let pool = payload_pool(&self.pool, &self.small_pool, datagram.len().saturating_sub(GAFFER_HEADER_SIZE));
+        let packet = CompleteGafferPacket::deserialize_pooled(datagram, pool)?;
+        let extended = self.extend_seq(&addr, packet.seq);
+        return Ok((packet, extended));
+      },
+    };
+
+    let (kind, seq, ack_seq, ack_field, message_id) = read_header(datagram)?;
+    let pool = payload_pool(&self.pool, &self.small_pool, datagram.len().saturating_sub(GAFFER_HEADER_SIZE + GAFFER_SESSION_SIZE + GAFFER_TAG_SIZE));
+    let mut payload = pool.take();
+    let opened = match connection.cipher {
+      Some(ref mut cipher) => cipher.open(seq, &connection.received, datagram, &mut payload),
+      None => Err(Rejected::Unauthenticated),
+    };
+    let (session, extended) = match opened {
+      Ok(opened) => opened,
+      Err(rejected) => {
+        pool.give(payload);
+        match rejected {
+          Rejected::Replayed => self.stats.replayed += 1,
+          Rejected::Unauthenticated => self.stats.unauthenticated += 1,
+        }
+        return Err(rejected.into());
+      },
+    };
+    // Sequence numbers of a new session count from zero, and so do message ids after a restart
+    match session {
+      Session::Current => {},
+      Session::First => connection.received = ReplayWindow::with_size(self.config.replay_window),
+      Session::Replaced => {
+        connection.their_acks = ExternalAcks::new();
+        connection.received = ReplayWindow::with_size(self.config.replay_window);
+        connection.delivered = ReplayWindow::with_size(self.config.dedup_window);
+      },
+    }
+    if connection.pending_connect.take().is_some() {
+      self.events.push_back(GafferEvent::Connected(addr, connection.client_id.unwrap_or_default()));
+    }
+    let packet = CompleteGafferPacket {
+      kind,
+      seq,
+      ack_seq,
+      ack_field,
+      message_id,
+      payload: GafferPayload::pooled(payload, pool),
+    };
+    Ok((packet, extended))
+  }
+
+  fn receive(&mut self, addr: SocketAddr, packet: CompleteGafferPacket) -> Option<GafferPacket> {
+    let connection = self.connection_entry(addr);
+    connection.last_activity = Instant::now();
     connection.their_acks.ack(packet.seq);
+    connection.owes_ack |= packet.kind == PacketKind::Data || packet.kind == PacketKind::Probe;
+    let probe = connection.path_mtu.probe_seq();
     let dropped_packets = connection.waiting_packets.ack(packet.ack_seq, packet.ack_field);
-    connection.dropped_packets = dropped_packets.into_iter().map(|(_, p)| p).collect();
-    GafferPacket { addr: addr, payload: packet.payload }
+    if let Some(seq) = probe {
+      if dropped_packets.iter().any(|&(dropped, _)| dropped == seq) {
+        connection.path_mtu.probe_failed();
+      } else if !connection.waiting_packets.contains(seq) {
+        connection.path_mtu.probe_acked();
+      }
+    }
+    connection.dropped_packets.extend(dropped_packets.into_iter()
+      .filter(|&(seq, _)| Some(seq) != probe)
+      .map(|(_, p)| p));
+    match packet.kind {
+      PacketKind::Data => {},
+      PacketKind::Probe | PacketKind::Connect | PacketKind::Challenge | PacketKind::Response | PacketKind::Full | PacketKind::Disconnect | PacketKind::Hello | PacketKind::Ack => return None,
+    }
+
+    // A resend whose first copy did arrive, acked above all the same so the peer stops resending.
+    // Ones older than the window cannot be told apart and are delivered rather than lost.
+    let message_id = connection.delivered.extend(packet.message_id);
+    if connection.delivered.contains(message_id) {
+      self.stats.duplicates += 1;
+      return None;
+    }
+    connection.delivered.accept(message_id);
+    Some(GafferPacket::new(addr, packet.payload))
+  }
+
+  fn connection_entry(&mut self, addr: SocketAddr) -> &mut Connection {
+    let config = &self.config;
+    self.connections.entry(addr).or_insert_with(|| {
+      let mut connection = if config.path_mtu_discovery {
+        Connection::with_path_mtu(PathMtu::search(config.path_mtu_min, config.path_mtu_max))
+      } else {
+        Connection::with_path_mtu(PathMtu::fixed(config.mtu))
+      };
+      connection.received = ReplayWindow::with_size(config.replay_window);
+      connection.delivered = ReplayWindow::with_size(config.dedup_window);
+      connection
+    })
+  }
+}
+
+/// Seconds since the unix epoch, tokens and cookies expire by it
+#[cfg(feature = "crypto")]
+fn unix_now() -> u64 {
+  SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or(0)
+}
+
+/// Bytes of every datagram on `connection` that are not payload
+#[cfg(feature = "crypto")]
+fn overhead(connection: &Connection) -> usize {
+  match connection.cipher {
+    Some(_) => GAFFER_HEADER_SIZE + GAFFER_SESSION_SIZE + GAFFER_TAG_SIZE,
+    None => GAFFER_HEADER_SIZE,
+  }
+}
+
+#[cfg(not(feature = "crypto"))]
+fn overhead(_connection: &Connection) -> usize {
+  GAFFER_HEADER_SIZE
+}
+
+/// Encrypts an encoded datagram if the connection has keys
+#[cfg(feature = "crypto")]
+fn seal(connection: &mut Connection, seq: u16, datagram: &mut Vec<u8>) {
+  if let Some(ref mut cipher) = connection.cipher {
+    cipher.seal(seq, datagram);
+  }
+}
+
+/// The pool a received payload of `len` bytes is decoded into, see `SMALL_BUFFER_SIZE`
+fn payload_pool<'a>(pool: &'a BufferPool, small_pool: &'a BufferPool, len: usize) -> &'a BufferPool {
+  if len <= SMALL_BUFFER_SIZE { small_pool } else { pool }
+}
+
+#[cfg(not(feature = "crypto"))]
+fn seal(_connection: &mut Connection, _seq: u16, _datagram: &mut Vec<u8>) {}
+
+
+/// What was left over when a socket shut down
+#[derive(Debug, Default)]
+pub struct ShutdownReport {
+  /// Packets that were never confirmed, see `GafferState::finish_shutdown`
+  pub unconfirmed: Vec<GafferPacket>,
+  /// Packets received while waiting for acks, in the order they arrived
+  pub received: Vec<GafferPacket>,
+}
+
+/// A send that failed, possibly after resending some dropped packets
+///
+/// Returned inside the `io::Error` from `send`, recover it with `get_ref` and `downcast_ref`.
+/// The packet that failed and any not yet attempted are back in `Connection::dropped_packets`,
+/// so the next send or `update` retries them with their message ids. A connect request that
+/// fails in `update` comes back the same way with nothing requeued, it goes out again on the
+/// next call.
+#[derive(Debug)]
+pub struct PartialSend {
+  /// Peer the failed send was for
+  pub addr: SocketAddr,
+  /// Dropped packets that went out before the failure
+  pub resent: usize,
+  /// Packets put back to be retried on the next send, the one that failed included
+  pub requeued: usize,
+  pub cause: io::Error,
+}
+
+impl fmt::Display for PartialSend {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "send failed after resending {} packets ({} requeued): {}", self.resent, self.requeued, self.cause)
+  }
+}
+
+impl Error for PartialSend {
+  fn source(&self) -> Option<&(dyn Error + 'static)> {
+    Some(&self.cause)
+  }
+}
+
+impl From<PartialSend> for io::Error {
+  fn from(partial: PartialSend) -> io::Error {
+    io::Error::new(partial.cause.kind(), partial)
   }
 }
 
+/// A packet whose payload does not fit the configured MTU
+///
+/// Returned inside an `InvalidInput` `io::Error` from `send`, recover it with `get_ref` and
+/// `downcast_ref`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PayloadTooLarge {
+  pub len: usize,
+  pub max: usize,
+}
+
+impl fmt::Display for PayloadTooLarge {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "payload of {} bytes is over the {} byte limit", self.len, self.max)
+  }
+}
 
+impl Error for PayloadTooLarge {}
+
+impl From<PayloadTooLarge> for io::Error {
+  fn from(err: PayloadTooLarge) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, err)
+  }
+}
 
 pub mod helpers {
   use connection::Connection;
 
   use packet::{
     CompleteGafferPacket,
-    GafferPacket
+    GafferPacket,
+    PacketKind
   };
 
   pub fn assemble_packet( seq_num: u16, p: GafferPacket, connection: &Connection) -> CompleteGafferPacket {
     CompleteGafferPacket {
+      kind: PacketKind::Data,
       seq: seq_num,
       ack_seq: connection.their_acks.last_seq,
       ack_field: connection.their_acks.field,
+      message_id: p.message_id.unwrap_or_default(),
       payload: p.payload
     }
   }
@@ -1,32 +1,83 @@
 use std::io;
 
+use std::collections::VecDeque;
+
+use std::net::SocketAddr;
+
+use std::time::{Duration, Instant};
+
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use mio::{Evented, Poll, PollOpt, Ready, Token};
 use mio::udp::UdpSocket;
 
-use socket::GafferState;
+use config::GafferConfig;
+
+use connection::Connection;
+
+use filter::{AddressFilter, IpRange};
+
+#[cfg(feature = "crypto")]
+use crypto::PacketKeys;
+
+use event::GafferEvent;
+
+use socket::{batch, GafferState, PartialSend, ShutdownReport, SHUTDOWN_POLL};
 use addr::ToSingleSocketAddr;
 
-use packet::{
-  CompleteGafferPacket,
-  GafferPacket,
-  GAFFER_MTU,
-};
+use packet::{DisconnectReason, GafferPacket};
+
+use stats::GafferStats;
+
+#[cfg(feature = "crypto")]
+use token::ConnectToken;
 
 #[allow(dead_code)]
 pub struct GafferSocket {
   udp_socket: UdpSocket,
   state: GafferState,
-  recv_buffer: [u8; GAFFER_MTU]
+  recv_buffer: Vec<u8>,
+  outbound: VecDeque<(SocketAddr, Vec<u8>)>,
+  batch_buffers: Vec<Vec<u8>>,
+  gso: bool,
+  gro: bool,
+  offload_buffer: Vec<u8>,
+  coalesced: VecDeque<GafferPacket>,
+  shutdown: Option<Shutdown>,
+}
+
+/// Progress of a shutdown, driven by `GafferSocket::poll_shutdown`
+struct Shutdown {
+  deadline: Instant,
+  received: Vec<GafferPacket>,
+  /// Set once every peer was disconnected
+  unconfirmed: Option<Vec<GafferPacket>>,
 }
 
 impl GafferSocket {
   pub fn bind<A: ToSingleSocketAddr>(addr: A) -> io::Result<Self> {
-    let first_addr = addr.to_single_socket_addr().unwrap();
-    UdpSocket::bind(&first_addr).map(|sock| {
-      GafferSocket {
+    GafferSocket::bind_with_config(addr, GafferConfig::new())
+  }
+
+  /// Bind with socket options and protocol settings from `config`
+  pub fn bind_with_config<A: ToSingleSocketAddr>(addr: A, config: GafferConfig) -> io::Result<Self> {
+    let first_addr = addr.to_single_socket_addr()?;
+    config.bind_udp(first_addr).and_then(UdpSocket::from_socket).and_then(|sock| {
+      let mut state = GafferState::with_config(config);
+      state.set_local_addr(sock.local_addr()?);
+      Ok(GafferSocket {
         udp_socket: sock,
-        state: GafferState::new(),
-        recv_buffer: [0; GAFFER_MTU]
-      }
+        recv_buffer: vec![0; state.recv_buffer_size()],
+        state,
+        outbound: VecDeque::new(),
+        batch_buffers: Vec::new(),
+        gso: false,
+        gro: false,
+        offload_buffer: Vec::new(),
+        coalesced: VecDeque::new(),
+        shutdown: None,
+      })
     })
   }
 
@@ -37,31 +88,138 @@ impl GafferSocket {
   /// - Identify dropped packets from message header
   /// - Forget own acked packets
   /// - Enqueue Sure-Dropped packets into resubmit-queue
+  ///
+  /// A datagram larger than the configured MTU is discarded with an `InvalidData` error, and
+  /// counted in `stats`. Path MTU probes are handled here and never returned.
   pub fn recv(&mut self) -> io::Result<Option<GafferPacket>> {
-    self.udp_socket.recv_from(&mut self.recv_buffer)
-      .and_then(|opt| {
-        match opt {
-          Some((len, addr)) => {
-            // TODO: Fix to_vec, it is suboptimal here
-            CompleteGafferPacket::deserialize(self.recv_buffer[..len].to_vec())
-              .map(|packet| Some(self.state.receive(addr, packet)))
-          },
-          None => Ok(None)
-        }
-      })
+    if self.gro {
+      return self.recv_coalesced();
+    }
+    loop {
+      let (len, addr) = match self.udp_socket.recv_from(&mut self.recv_buffer)? {
+        Some(received) => received,
+        None => return Ok(None),
+      };
+      let received = self.state.process_datagram(addr, &self.recv_buffer[..len]);
+      self.send_control();
+      if let Some(packet) = received? {
+        return Ok(Some(packet));
+      }
+    }
   }
 
   /// Send a normal message
   ///
   /// - Send dropped packets
   /// - Send packet
+  ///
+  /// If a send fails, the packet that failed and any not yet sent are kept for the next send and
+  /// the error carries a `PartialSend`. A payload over the MTU is refused up front with
+  /// `PayloadTooLarge`.
+  ///
+  /// Returns `Ok(None)` when the socket would block. The encoded packet is kept in the outbound
+  /// queue and goes out on a later `flush`.
   pub fn send(&mut self, p: GafferPacket) -> io::Result<Option<usize>> {
-    let dropped_packets = self.state.dropped_packets(p.addr);
-    for packet in dropped_packets.into_iter() {
-      // TODO: if this fails, a bunch of packets are dropped
-      try!(self.single_send(packet));
+    self.state.check_send(&p)?;
+    let addr = p.addr;
+    let resent = self.resend_dropped(addr)?;
+    self.single_send(p).map_err(|err| {
+      self.state.requeue_unsent(addr, 1);
+      PartialSend { addr, resent, requeued: 1, cause: err }.into()
+    })
+  }
+
+  /// Resend dropped packets for every known connection
+  ///
+  /// Meant to be called periodically, see `blocking::GafferSocket::update`. Resends that would
+  /// block are queued for `flush`, while path MTU probes and connect requests that would block
+  /// are retried on a later call. Returns the number of packets resent or queued.
+  pub fn update(&mut self) -> io::Result<usize> {
+    let mut resent = 0;
+    for addr in self.state.addrs_with_dropped_packets() {
+      resent += self.resend_dropped(addr)?;
+    }
+    for (addr, probe) in self.state.preprocess_probes() {
+      let result = if self.outbound.is_empty() {
+        self.udp_socket.send_to(&probe, &addr)
+          .and_then(|sent| sent.ok_or_else(|| io::ErrorKind::WouldBlock.into()))
+      } else {
+        Err(io::ErrorKind::WouldBlock.into())
+      };
+      self.state.probe_sent(addr, &result);
+      self.state.recycle(probe);
+    }
+    self.send_connects()?;
+    self.state.queue_hellos();
+    self.state.queue_acks();
+    self.send_control();
+    Ok(resent)
+  }
+
+  fn send_connects(&mut self) -> io::Result<()> {
+    for (addr, datagram) in self.state.preprocess_connects() {
+      let result = self.udp_socket.send_to(&datagram, &addr);
+      self.state.recycle(datagram);
+      if let Err(err) = result {
+        return Err(PartialSend { addr, resent: 0, requeued: 0, cause: err }.into());
+      }
     }
-    self.single_send(p)
+    Ok(())
+  }
+
+  /// Sends the datagrams the protocol queued by itself, see `blocking::send_control`
+  ///
+  /// They keep their place behind the outbound queue like any other send, and what would block
+  /// joins it for `flush`.
+  fn send_control(&mut self) {
+    while let Some((addr, datagram)) = self.state.poll_control() {
+      // Acks and disconnects are sequenced, so they cannot overtake what is already queued
+      if !self.outbound.is_empty() {
+        self.outbound.push_back((addr, datagram));
+        continue;
+      }
+      match self.udp_socket.send_to(&datagram, &addr) {
+        Ok(None) => self.outbound.push_back((addr, datagram)),
+        _ => self.state.recycle(datagram),
+      }
+    }
+  }
+
+  /// Largest payload known to reach `addr` in one datagram, see `GafferState::path_max_payload`
+  pub fn path_max_payload(&self, addr: &SocketAddr) -> usize {
+    self.state.path_max_payload(addr)
+  }
+
+  /// Resends the dropped packets for `addr` as one batch, queueing what would block for `flush`
+  fn resend_dropped(&mut self, addr: SocketAddr) -> io::Result<usize> {
+    let packets = self.state.dropped_packets(addr);
+    if packets.is_empty() {
+      return Ok(0);
+    }
+    let count = packets.len();
+    let mut datagrams = self.state.preprocess_batch(packets);
+
+    if !self.outbound.is_empty() {
+      self.outbound.extend(datagrams);
+      return Ok(count);
+    }
+
+    let sent = match batch::send_offload(&self.udp_socket, &datagrams, &mut self.gso) {
+      Ok(sent) => sent,
+      Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => 0,
+      Err(err) => {
+        for (_, datagram) in datagrams {
+          self.state.recycle(datagram);
+        }
+        self.state.requeue_unsent(addr, count);
+        return Err(PartialSend { addr, resent: 0, requeued: count, cause: err }.into());
+      },
+    };
+    self.outbound.extend(datagrams.drain(sent..));
+    for (_, datagram) in datagrams {
+      self.state.recycle(datagram);
+    }
+    Ok(count)
   }
 
   ///
@@ -75,7 +233,289 @@ impl GafferSocket {
   fn single_send(&mut self, p: GafferPacket) -> io::Result<Option<usize>> {
     let (destination, payload) = self.state.preprocess_packet(p);
 
-    self.udp_socket.send_to(payload.as_ref(), &destination)
+    // Anything already queued has to go out first, or the peer sees sequence numbers reordered
+    if !self.outbound.is_empty() {
+      self.outbound.push_back((destination, payload));
+      return Ok(None);
+    }
+
+    let res = self.udp_socket.send_to(payload.as_ref(), &destination);
+    if let Ok(None) = res {
+      self.outbound.push_back((destination, payload));
+    } else {
+      self.state.recycle(payload);
+    }
+    res
+  }
+
+  /// Send several packets with as few syscalls as the platform allows
+  ///
+  /// See `blocking::GafferSocket::send_batch`. Returns how many datagrams went out right away,
+  /// the rest are kept in the outbound queue. If the send fails outright, the whole batch is put
+  /// back in front of each peer's dropped packets and the error is a `PartialSend`.
+  pub fn send_batch(&mut self, packets: Vec<GafferPacket>) -> io::Result<usize> {
+    for p in &packets {
+      self.state.check_send(p)?;
+    }
+    let packets = self.state.with_dropped_packets(packets);
+    let mut datagrams = self.state.preprocess_batch(packets);
+
+    if !self.outbound.is_empty() {
+      self.outbound.extend(datagrams);
+      return Ok(0);
+    }
+
+    let sent = match batch::send_offload(&self.udp_socket, &datagrams, &mut self.gso) {
+      Ok(sent) => sent,
+      Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => 0,
+      Err(err) => {
+        let requeued = datagrams.len();
+        self.state.requeue_unsent_batch(&datagrams);
+        let addr = datagrams[0].0;
+        for (_, datagram) in datagrams {
+          self.state.recycle(datagram);
+        }
+        return Err(PartialSend { addr, resent: 0, requeued, cause: err }.into());
+      },
+    };
+    self.outbound.extend(datagrams.drain(sent..));
+    for (_, datagram) in datagrams {
+      self.state.recycle(datagram);
+    }
+    Ok(sent)
+  }
+
+  /// Receive up to `max` packets that are already waiting
+  ///
+  /// Returns an empty list when nothing has arrived. See `blocking::GafferSocket::recv_batch`.
+  pub fn recv_batch(&mut self, max: usize) -> io::Result<Vec<GafferPacket>> {
+    if self.gro {
+      let mut packets = Vec::new();
+      while packets.len() < max {
+        match self.recv_coalesced()? {
+          Some(packet) => packets.push(packet),
+          None => break,
+        }
+      }
+      return Ok(packets);
+    }
+    if self.batch_buffers.len() < max {
+      self.batch_buffers.resize(max, vec![0; self.state.recv_buffer_size()]);
+    }
+    match batch::recv_batch(&self.udp_socket, &mut self.batch_buffers[..max]) {
+      Ok(received) => {
+        let packets = self.state.receive_batch(&self.batch_buffers, received);
+        self.send_control();
+        Ok(packets)
+      },
+      Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => Ok(Vec::new()),
+      Err(err) => Err(err),
+    }
+  }
+
+  /// The address the socket is bound to, useful after binding to port 0
+  pub fn local_addr(&self) -> io::Result<SocketAddr> {
+    self.udp_socket.local_addr()
+  }
+
+  /// Counters for received traffic that was discarded
+  pub fn stats(&self) -> &GafferStats {
+    self.state.stats()
+  }
+
+  /// Addresses of every peer with a connection
+  pub fn peers(&self) -> Vec<SocketAddr> {
+    self.state.peers()
+  }
+
+  pub fn connection(&self, addr: &SocketAddr) -> Option<&Connection> {
+    self.state.connection(addr)
+  }
+
+  /// Drops all state for a peer, see `GafferState::forget`
+  pub fn forget_peer(&mut self, addr: &SocketAddr) -> Option<Connection> {
+    self.state.forget(addr)
+  }
+
+  /// Tells a peer we are leaving and forgets it, see `GafferState::disconnect`
+  pub fn disconnect(&mut self, addr: SocketAddr, reason: DisconnectReason) -> io::Result<Connection> {
+    let connection = self.state.disconnect(addr, reason)?;
+    self.send_control();
+    Ok(connection)
+  }
+
+  /// Which addresses datagrams are accepted from, see `GafferState::filter_mut`
+  pub fn filter_mut(&mut self) -> &mut AddressFilter {
+    self.state.filter_mut()
+  }
+
+  /// Shuts a range out for `duration` and forgets its connections, see `GafferState::ban`
+  pub fn ban(&mut self, range: IpRange, duration: Duration) -> Vec<SocketAddr> {
+    self.state.ban(range, duration)
+  }
+
+  /// Encrypts everything to and from a peer, see `GafferState::set_keys`
+  #[cfg(feature = "crypto")]
+  pub fn set_peer_keys(&mut self, addr: SocketAddr, keys: &PacketKeys) -> io::Result<()> {
+    self.state.set_keys(addr, keys)
+  }
+
+  /// Connects to a server with a token from the backend, see `blocking::GafferSocket::connect`
+  #[cfg(feature = "crypto")]
+  pub fn connect(&mut self, server: SocketAddr, token: &ConnectToken) -> io::Result<()> {
+    self.state.connect(server, token)?;
+    self.send_connects()
+  }
+
+  /// Next connection event, such as a client being let in with a connect token
+  pub fn poll_event(&mut self) -> Option<GafferEvent> {
+    self.state.poll_event()
+  }
+
+  /// Turns UDP segmentation offload (GSO and GRO) on or off, on Linux
+  ///
+  /// See `blocking::GafferSocket::set_segmentation_offload`.
+  pub fn set_segmentation_offload(&mut self, enabled: bool) -> io::Result<bool> {
+    let enabled = batch::set_offload(&self.udp_socket, enabled)?;
+    self.gso = enabled;
+    self.gro = enabled;
+    self.offload_buffer = if enabled { vec![0; batch::MAX_OFFLOAD_BYTES] } else { Vec::new() };
+    Ok(enabled)
+  }
+
+  fn recv_coalesced(&mut self) -> io::Result<Option<GafferPacket>> {
+    loop {
+      if let Some(packet) = self.coalesced.pop_front() {
+        return Ok(Some(packet));
+      }
+      match batch::recv_segments(&self.udp_socket, &mut self.offload_buffer) {
+        Ok((len, addr, segment_size)) => {
+          let packets = self.state.receive_segments(&self.offload_buffer[..len], segment_size, addr);
+          self.send_control();
+          self.coalesced.extend(packets);
+        },
+        Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+        Err(err) => return Err(err),
+      }
+    }
+  }
+
+  /// Send queued packets that previously hit `WouldBlock`
+  ///
+  /// Call when the socket is writable. Returns the number of bytes still pending.
+  pub fn flush(&mut self) -> io::Result<usize> {
+    while let Some((destination, payload)) = self.outbound.pop_front() {
+      match self.udp_socket.send_to(payload.as_ref(), &destination) {
+        Ok(Some(_)) => self.state.recycle(payload),
+        Ok(None) => {
+          self.outbound.push_front((destination, payload));
+          break;
+        },
+        Err(err) => {
+          self.outbound.push_front((destination, payload));
+          return Err(err);
+        }
+      }
+    }
+    Ok(self.pending_bytes())
+  }
+
+  /// Stops sending and gives packets in flight up to `timeout` to be confirmed
+  ///
+  /// Never blocks: new sends are refused from here on, and the event loop drives the rest by
+  /// calling `poll_shutdown` until it returns the report.
+  pub fn begin_shutdown(&mut self, timeout: Duration) {
+    self.state.begin_shutdown();
+    if self.shutdown.is_none() {
+      self.shutdown = Some(Shutdown { deadline: Instant::now() + timeout, received: Vec::new(), unconfirmed: None });
+    }
+  }
+
+  /// Takes a shutdown begun by `begin_shutdown` as far as it can go without blocking
+  ///
+  /// Call whenever the socket is readable or writable, and at the latest after
+  /// `next_shutdown_poll`. Receives acks, resends and flushes until nothing is left unconfirmed
+  /// or the timeout is up, then disconnects every peer. Returns the report, like
+  /// `blocking::GafferSocket::shutdown`, once the last `Disconnect` copy went out. Packets still
+  /// in the outbound queue by then are counted as unconfirmed and discarded. Refused with
+  /// `InvalidInput` if no shutdown was begun.
+  pub fn poll_shutdown(&mut self) -> io::Result<Option<ShutdownReport>> {
+    let mut shutdown = self.shutdown.take()
+      .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "shutdown was not begun"))?;
+    if shutdown.unconfirmed.is_none() {
+      // Errors are for single datagrams
+      while Instant::now() < shutdown.deadline {
+        match self.recv() {
+          Ok(Some(packet)) => shutdown.received.push(packet),
+          Ok(None) => break,
+          Err(_) => {},
+        }
+      }
+      // Failed resends are retried next time round
+      let _ = self.update();
+      let _ = self.flush();
+      if Instant::now() >= shutdown.deadline || (!self.state.has_unconfirmed() && self.outbound.is_empty()) {
+        shutdown.unconfirmed = Some(self.state.finish_shutdown());
+        while let Some((_, payload)) = self.outbound.pop_front() {
+          self.state.recycle(payload);
+        }
+      }
+    }
+    if shutdown.unconfirmed.is_some() {
+      self.send_control();
+      // A peer the `Disconnect` cannot reach times out on its own
+      let flushed = self.flush().is_err() || self.outbound.is_empty();
+      // The later copies of each `Disconnect` are spaced out
+      if flushed && self.state.next_farewell().is_none() {
+        while let Some((_, payload)) = self.outbound.pop_front() {
+          self.state.recycle(payload);
+        }
+        return Ok(Some(ShutdownReport { unconfirmed: shutdown.unconfirmed.unwrap_or_default(), received: shutdown.received }));
+      }
+    }
+    self.shutdown = Some(shutdown);
+    Ok(None)
+  }
+
+  /// Longest the event loop may wait before calling `poll_shutdown` again
+  ///
+  /// `None` when no shutdown is under way. Pass it as the timeout to `mio::Poll::poll`.
+  pub fn next_shutdown_poll(&self) -> Option<Duration> {
+    let shutdown = self.shutdown.as_ref()?;
+    let now = Instant::now();
+    let at = match shutdown.unconfirmed {
+      None => (now + SHUTDOWN_POLL).min(shutdown.deadline),
+      // Otherwise only waiting for the socket to take the last copies
+      Some(_) => self.state.next_farewell().unwrap_or(now + SHUTDOWN_POLL),
+    };
+    Some(at.saturating_duration_since(now))
+  }
+
+  /// Number of encoded bytes waiting in the outbound queue
+  pub fn pending_bytes(&self) -> usize {
+    self.outbound.iter().map(|(_, payload)| payload.len()).sum()
+  }
+}
+
+/// Registers the underlying udp socket, so a `GafferSocket` can sit in a user's `mio::Poll`
+impl Evented for GafferSocket {
+  fn register(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> io::Result<()> {
+    self.udp_socket.register(poll, token, interest, opts)
+  }
+
+  fn reregister(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> io::Result<()> {
+    self.udp_socket.reregister(poll, token, interest, opts)
+  }
+
+  fn deregister(&self, poll: &Poll) -> io::Result<()> {
+    self.udp_socket.deregister(poll)
+  }
+}
+
+#[cfg(unix)]
+impl AsRawFd for GafferSocket {
+  fn as_raw_fd(&self) -> RawFd {
+    self.udp_socket.as_raw_fd()
   }
 }
 
@@ -83,7 +523,12 @@ impl GafferSocket {
 mod tests{
 
   use super::*;
-  use packet::GafferPacket;
+  use packet::{GafferPacket, GAFFER_MTU, GAFFER_VERSION};
+  use socket::{PayloadTooLarge, DISCONNECT_COPIES};
+
+  use mio::{Events, Poll, PollOpt, Ready, Token};
+
+  use std::thread;
 
   #[test]
   fn recv_doesnt_block() {
@@ -112,4 +557,528 @@ mod tests{
     let addr = unwrap_pkt.addr;
     assert_eq!(addr.to_string(), "127.0.0.1:45214");
   }
+
+  #[test]
+  fn registers_with_poll() {
+    let mut send_sock = GafferSocket::bind("0.0.0.0:45216").unwrap();
+    let mut recv_sock = GafferSocket::bind("0.0.0.0:45217").unwrap();
+    let poll = Poll::new().unwrap();
+    poll.register(&recv_sock, Token(7), Ready::readable(), PollOpt::edge()).unwrap();
+
+    send_sock.send(GafferPacket::new("127.0.0.1:45217", vec![1, 2, 3])).unwrap();
+
+    let mut events = Events::with_capacity(4);
+    poll.poll(&mut events, Some(Duration::from_secs(1))).unwrap();
+    let event = events.iter().next().unwrap();
+    assert_eq!(event.token(), Token(7));
+    assert!(event.readiness().is_readable());
+
+    let packet = recv_sock.recv().unwrap().unwrap();
+    assert_eq!(packet.payload, vec![1, 2, 3]);
+  }
+
+  #[test]
+  fn queued_packets_go_out_on_flush() {
+    let mut send_sock = GafferSocket::bind("0.0.0.0:45218").unwrap();
+    let mut recv_sock = GafferSocket::bind("0.0.0.0:45219").unwrap();
+    let destination = "127.0.0.1:45219".parse().unwrap();
+
+    // Pretend an earlier send hit WouldBlock
+    let (_, blocked) = send_sock.state.preprocess_packet(GafferPacket::new(destination, vec![1]));
+    send_sock.outbound.push_back((destination, blocked));
+
+    let send_res = send_sock.send(GafferPacket::new(destination, vec![2]));
+    assert_eq!(send_res.unwrap(), None);
+    assert!(send_sock.pending_bytes() > 0);
+
+    assert_eq!(send_sock.flush().unwrap(), 0);
+
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(recv_sock.recv().unwrap().unwrap().payload, vec![1]);
+    assert_eq!(recv_sock.recv().unwrap().unwrap().payload, vec![2]);
+  }
+
+  #[test]
+  fn failed_resends_are_requeued() {
+    let mut sock = GafferSocket::bind("127.0.0.1:45220").unwrap();
+    // Not routable from a loopback-bound socket, so every send fails
+    let destination = "198.51.100.1:45221".parse().unwrap();
+    for _ in 0..3 {
+      sock.state.requeue_dropped(destination, vec![GafferPacket::new(destination, vec![9])]);
+    }
+
+    let err = sock.send(GafferPacket::new(destination, vec![1])).unwrap_err();
+    {
+      let partial = err.get_ref().unwrap().downcast_ref::<PartialSend>().unwrap();
+      assert_eq!(partial.resent, 0);
+      assert_eq!(partial.requeued, 3);
+    }
+    assert_eq!(sock.state.dropped_packets(destination).len(), 3);
+  }
+
+  #[test]
+  fn failed_batches_are_requeued() {
+    let mut sock = GafferSocket::bind("127.0.0.1:0").unwrap();
+    let destination = "198.51.100.1:45221".parse().unwrap();
+    sock.state.requeue_dropped(destination, vec![GafferPacket::new(destination, vec![9])]);
+
+    let packets = (0..2).map(|idx| GafferPacket::new(destination, vec![idx])).collect();
+    let err = sock.send_batch(packets).unwrap_err();
+    assert_eq!(err.get_ref().unwrap().downcast_ref::<PartialSend>().unwrap().requeued, 3);
+    assert_eq!(sock.pending_bytes(), 0);
+    assert_eq!(sock.state.dropped_packets(destination).len(), 3);
+  }
+
+  #[test]
+  fn recv_batch_doesnt_block() {
+    let mut sock = GafferSocket::bind("127.0.0.1:45234").unwrap();
+    assert_eq!(sock.recv_batch(4).unwrap(), Vec::new());
+  }
+
+  #[test]
+  fn binds_with_config() {
+    let mut config = GafferConfig::new();
+    config.send_buffer_size = Some(1 << 18);
+    let mut send_sock = GafferSocket::bind_with_config("127.0.0.1:45239", config.clone()).unwrap();
+    let mut recv_sock = GafferSocket::bind_with_config("127.0.0.1:45240", config).unwrap();
+
+    send_sock.send(GafferPacket::new("127.0.0.1:45240", vec![4])).unwrap();
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(recv_sock.recv().unwrap().unwrap().payload, vec![4]);
+  }
+
+  #[test]
+  fn tracks_peers() {
+    let mut sock = GafferSocket::bind("127.0.0.1:0").unwrap();
+    let local_addr = sock.local_addr().unwrap();
+    assert!(local_addr.port() != 0);
+
+    let peer = "127.0.0.1:45241".parse().unwrap();
+    sock.send(GafferPacket::new(peer, vec![1])).unwrap();
+    assert_eq!(sock.peers(), vec![peer]);
+    assert_eq!(sock.connection(&peer).unwrap().seq_num, 1);
+
+    assert!(sock.forget_peer(&peer).is_some());
+    assert!(sock.peers().is_empty());
+    assert!(sock.connection(&peer).is_none());
+  }
+
+  #[test]
+  fn discards_truncated_datagrams() {
+    let mut config = GafferConfig::new();
+    config.mtu = 64;
+    let mut sock = GafferSocket::bind_with_config("127.0.0.1:0", config).unwrap();
+    let raw = ::std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+
+    let mut datagram = [0; 65];
+    datagram[0] = GAFFER_VERSION;
+    raw.send_to(&datagram, sock.local_addr().unwrap()).unwrap();
+    raw.send_to(&datagram[..64], sock.local_addr().unwrap()).unwrap();
+    thread::sleep(Duration::from_millis(50));
+
+    let err = sock.recv().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(sock.stats().truncated, 1);
+    assert_eq!(sock.recv().unwrap().unwrap().payload.len(), 52);
+  }
+
+  #[test]
+  fn refuses_oversized_payloads() {
+    let mut sock = GafferSocket::bind("127.0.0.1:0").unwrap();
+    let peer = "127.0.0.1:45242".parse().unwrap();
+    let max = GAFFER_MTU;
+
+    let err = sock.send(GafferPacket::new(peer, vec![0; max + 1])).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    assert_eq!(err.get_ref().unwrap().downcast_ref::<PayloadTooLarge>(), Some(&PayloadTooLarge { len: max + 1, max }));
+    assert!(sock.connection(&peer).is_none());
+
+    assert!(sock.send(GafferPacket::new(peer, vec![0; max])).is_ok());
+  }
+
+  #[cfg(feature = "crypto")]
+  #[test]
+  fn encrypts_with_peer_keys() {
+    use crypto::{GAFFER_SESSION_SIZE, GAFFER_TAG_SIZE};
+    use packet::{CompleteGafferPacket, PacketKind};
+
+    let mut config = GafferConfig::new();
+    config.require_encryption = true;
+    let mut sock = GafferSocket::bind_with_config("127.0.0.1:0", config.clone()).unwrap();
+    let mut peer = GafferSocket::bind_with_config("127.0.0.1:0", config).unwrap();
+    let sock_addr = sock.local_addr().unwrap();
+    let peer_addr = peer.local_addr().unwrap();
+
+    let err = sock.send(GafferPacket::new(peer_addr, vec![1])).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+
+    let keys = PacketKeys::new([3; 32], [4; 32]);
+    sock.set_peer_keys(peer_addr, &keys).unwrap();
+    peer.set_peer_keys(sock_addr, &keys.reversed()).unwrap();
+    assert_eq!(sock.set_peer_keys(peer_addr, &keys).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+    let max = GAFFER_MTU - GAFFER_SESSION_SIZE - GAFFER_TAG_SIZE;
+    assert!(sock.send(GafferPacket::new(peer_addr, vec![0; max + 1])).is_err());
+    sock.send(GafferPacket::new(peer_addr, vec![0; max])).unwrap();
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(peer.recv().unwrap().unwrap().payload.len(), max);
+
+    // Plaintext from an address without keys never gets a connection
+    let stranger = ::std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let plain = CompleteGafferPacket { kind: PacketKind::Data, seq: 0, ack_seq: 0, ack_field: 0, message_id: 0, payload: vec![1].into() };
+    stranger.send_to(&plain.serialized(), peer_addr).unwrap();
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(peer.recv().unwrap_err().kind(), io::ErrorKind::InvalidData);
+    assert_eq!(peer.stats().unauthenticated, 1);
+    assert!(peer.connection(&stranger.local_addr().unwrap()).is_none());
+  }
+
+  #[cfg(feature = "crypto")]
+  #[test]
+  fn accepts_peers_that_start_over() {
+    let mut sock = GafferSocket::bind("127.0.0.1:0").unwrap();
+    let mut peer = GafferSocket::bind("127.0.0.1:0").unwrap();
+    let sock_addr = sock.local_addr().unwrap();
+    let peer_addr = peer.local_addr().unwrap();
+    let keys = PacketKeys::new([3; 32], [4; 32]);
+    sock.set_peer_keys(peer_addr, &keys).unwrap();
+    peer.set_peer_keys(sock_addr, &keys.reversed()).unwrap();
+
+    sock.send(GafferPacket::new(peer_addr, vec![1])).unwrap();
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(peer.recv().unwrap().unwrap().payload, vec![1]);
+
+    // Same keys, sequence numbers and message ids from zero, but a new session
+    sock.forget_peer(&peer_addr);
+    sock.set_peer_keys(peer_addr, &keys).unwrap();
+    sock.send(GafferPacket::new(peer_addr, vec![2])).unwrap();
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(peer.recv().unwrap().unwrap().payload, vec![2]);
+    assert_eq!(peer.stats().replayed, 0);
+  }
+
+  #[test]
+  fn rejects_replayed_and_far_sequence_numbers() {
+    use packet::{CompleteGafferPacket, PacketKind};
+
+    let mut sock = GafferSocket::bind("127.0.0.1:0").unwrap();
+    let sock_addr = sock.local_addr().unwrap();
+    let peer = ::std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let peer_addr = peer.local_addr().unwrap();
+    let datagram = |seq| {
+      CompleteGafferPacket { kind: PacketKind::Data, seq, ack_seq: 0, ack_field: 0, message_id: seq, payload: vec![seq as u8].into() }.serialized()
+    };
+
+    peer.send_to(&datagram(0), sock_addr).unwrap();
+    peer.send_to(&datagram(0), sock_addr).unwrap();
+    peer.send_to(&datagram(5000), sock_addr).unwrap();
+    peer.send_to(&datagram(2), sock_addr).unwrap();
+    thread::sleep(Duration::from_millis(50));
+
+    assert_eq!(sock.recv().unwrap().unwrap().payload, vec![0]);
+    assert_eq!(sock.recv().unwrap_err().kind(), io::ErrorKind::InvalidData);
+    assert_eq!(sock.recv().unwrap_err().kind(), io::ErrorKind::InvalidData);
+    assert_eq!(sock.recv().unwrap().unwrap().payload, vec![2]);
+    assert_eq!(sock.stats().replayed, 1);
+    assert_eq!(sock.stats().out_of_window, 1);
+    assert_eq!(sock.connection(&peer_addr).unwrap().their_acks.last_seq, 2);
+  }
+
+  #[test]
+  fn catches_up_after_long_losses() {
+    use packet::{CompleteGafferPacket, PacketKind};
+
+    let config = GafferConfig { replay_window: 256, ..GafferConfig::new() };
+    let mut sock = GafferSocket::bind_with_config("127.0.0.1:0", config).unwrap();
+    let sock_addr = sock.local_addr().unwrap();
+    let peer = ::std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let datagram = |seq| {
+      CompleteGafferPacket { kind: PacketKind::Data, seq, ack_seq: 0, ack_field: 0, message_id: seq, payload: vec![seq as u8].into() }.serialized()
+    };
+
+    // Late, but inside the configured window
+    for &seq in &[200, 10, 5000, 5001, 5002, 5003] {
+      peer.send_to(&datagram(seq), sock_addr).unwrap();
+    }
+    thread::sleep(Duration::from_millis(50));
+
+    assert_eq!(sock.recv().unwrap().unwrap().payload, vec![200]);
+    assert_eq!(sock.recv().unwrap().unwrap().payload, vec![10]);
+    assert_eq!(sock.recv().unwrap_err().kind(), io::ErrorKind::InvalidData);
+    assert_eq!(sock.recv().unwrap_err().kind(), io::ErrorKind::InvalidData);
+    assert_eq!(sock.recv().unwrap().unwrap().payload, vec![5002u16 as u8]);
+    assert_eq!(sock.recv().unwrap().unwrap().payload, vec![5003u16 as u8]);
+    assert_eq!(sock.stats().out_of_window, 2);
+  }
+
+  #[test]
+  fn drops_resent_duplicates() {
+    use packet::{CompleteGafferPacket, PacketKind};
+
+    let mut sock = GafferSocket::bind("127.0.0.1:0").unwrap();
+    let sock_addr = sock.local_addr().unwrap();
+    let peer = ::std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let peer_addr = peer.local_addr().unwrap();
+    let datagram = |seq, message_id| {
+      CompleteGafferPacket { kind: PacketKind::Data, seq, ack_seq: 0, ack_field: 0, message_id, payload: vec![message_id as u8].into() }.serialized()
+    };
+
+    // The second is a resend of the first under a new sequence number
+    peer.send_to(&datagram(0, 7), sock_addr).unwrap();
+    peer.send_to(&datagram(1, 7), sock_addr).unwrap();
+    peer.send_to(&datagram(2, 8), sock_addr).unwrap();
+    thread::sleep(Duration::from_millis(50));
+
+    assert_eq!(sock.recv().unwrap().unwrap().payload, vec![7]);
+    assert_eq!(sock.recv().unwrap().unwrap().payload, vec![8]);
+    assert_eq!(sock.stats().duplicates, 1);
+    // Still acked, so the peer stops resending it
+    assert_eq!(sock.connection(&peer_addr).unwrap().their_acks.field & 1, 1);
+  }
+
+  #[test]
+  fn delivers_resends_older_than_the_dedup_window() {
+    use packet::{CompleteGafferPacket, PacketKind};
+
+    let config = GafferConfig { dedup_window: 64, ..GafferConfig::new() };
+    let mut sock = GafferSocket::bind_with_config("127.0.0.1:0", config).unwrap();
+    let sock_addr = sock.local_addr().unwrap();
+    let peer = ::std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let datagram = |seq, message_id| {
+      CompleteGafferPacket { kind: PacketKind::Data, seq, ack_seq: 0, ack_field: 0, message_id, payload: vec![message_id as u8].into() }.serialized()
+    };
+
+    peer.send_to(&datagram(0, 100), sock_addr).unwrap();
+    // Resent long after many newer messages, its first copy never arrived
+    peer.send_to(&datagram(1, 10), sock_addr).unwrap();
+    thread::sleep(Duration::from_millis(50));
+
+    assert_eq!(sock.recv().unwrap().unwrap().payload, vec![100]);
+    assert_eq!(sock.recv().unwrap().unwrap().payload, vec![10]);
+    assert_eq!(sock.stats().duplicates, 0);
+  }
+
+  #[test]
+  fn resends_keep_their_message_id() {
+    let mut sock = GafferSocket::bind("127.0.0.1:0").unwrap();
+    let peer = "127.0.0.1:45243".parse().unwrap();
+    let message_ids = |sock: &mut GafferSocket, next_seq| -> Vec<Option<u16>> {
+      let waiting = &mut sock.state.connections.get_mut(&peer).unwrap().waiting_packets;
+      waiting.drain(next_seq).into_iter().map(|(_, p)| p.message_id).collect()
+    };
+
+    sock.send(GafferPacket::new(peer, vec![1])).unwrap();
+    sock.send(GafferPacket::new(peer, vec![2])).unwrap();
+    assert_eq!(message_ids(&mut sock, 2), vec![Some(0), Some(1)]);
+
+    let mut resend = GafferPacket::new(peer, vec![1]);
+    resend.message_id = Some(0);
+    sock.state.requeue_dropped(peer, vec![resend]);
+    sock.send(GafferPacket::new(peer, vec![3])).unwrap();
+    assert_eq!(message_ids(&mut sock, 4), vec![Some(0), Some(2)]);
+  }
+
+  #[test]
+  fn drops_traffic_over_rate_limits() {
+    use event::GafferEvent;
+    use limit::RateLimit;
+
+    let mut config = GafferConfig::new();
+    config.peer_rate_limit = Some(RateLimit::new(3, 1 << 20));
+    config.report_abuse = true;
+    let mut sock = GafferSocket::bind_with_config("127.0.0.1:0", config).unwrap();
+    let mut peer = GafferSocket::bind("127.0.0.1:0").unwrap();
+    let sock_addr = sock.local_addr().unwrap();
+    let peer_addr = peer.local_addr().unwrap();
+
+    for idx in 0..5 {
+      peer.send(GafferPacket::new(sock_addr, vec![idx])).unwrap();
+    }
+    thread::sleep(Duration::from_millis(50));
+
+    let mut received = Vec::new();
+    while let Some(packet) = sock.recv().unwrap() {
+      received.push(packet.payload.into_vec());
+    }
+    assert_eq!(received, vec![vec![0], vec![1], vec![2]]);
+    assert_eq!(sock.stats().rate_limited, 2);
+    assert_eq!(sock.poll_event(), Some(GafferEvent::PeerAbusive(peer_addr)));
+    assert_eq!(sock.poll_event(), None);
+  }
+
+  #[test]
+  fn rejects_peers_once_full() {
+    use event::GafferEvent;
+
+    let mut config = GafferConfig::new();
+    config.max_connections = Some(1);
+    let mut sock = GafferSocket::bind_with_config("127.0.0.1:0", config).unwrap();
+    let mut first = GafferSocket::bind("127.0.0.1:0").unwrap();
+    let mut second = GafferSocket::bind("127.0.0.1:0").unwrap();
+    let sock_addr = sock.local_addr().unwrap();
+    let second_addr = second.local_addr().unwrap();
+
+    first.send(GafferPacket::new(sock_addr, vec![1])).unwrap();
+    thread::sleep(Duration::from_millis(20));
+    second.send(GafferPacket::new(sock_addr, vec![2])).unwrap();
+    thread::sleep(Duration::from_millis(20));
+
+    let mut received = Vec::new();
+    while let Some(packet) = sock.recv().unwrap() {
+      received.push(packet.payload.into_vec());
+    }
+    assert_eq!(received, vec![vec![1]]);
+    assert_eq!(sock.stats().rejected_full, 1);
+    assert_eq!(sock.peers(), vec![first.local_addr().unwrap()]);
+    assert_eq!(sock.poll_event(), Some(GafferEvent::PeerRejected(second_addr)));
+
+    thread::sleep(Duration::from_millis(20));
+    assert!(second.recv().unwrap().is_none());
+    assert_eq!(second.poll_event(), Some(GafferEvent::ServerFull(sock_addr)));
+    assert!(second.connection(&sock_addr).is_none());
+  }
+
+  #[test]
+  fn only_evicts_with_proof_of_address() {
+    use config::FullPolicy;
+
+    let mut config = GafferConfig::new();
+    config.max_connections = Some(1);
+    config.when_full = FullPolicy::EvictLeastRecent;
+    let err = GafferSocket::bind_with_config("127.0.0.1:0", config).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+  }
+
+  #[test]
+  #[cfg(feature = "crypto")]
+  fn evicts_least_recent_peer_once_full() {
+    use config::FullPolicy;
+    use event::GafferEvent;
+
+    /// Goes through the cookie exchange, then delivers the packet it held up
+    fn join(sock: &mut GafferSocket, client: &mut GafferSocket, payload: Vec<u8>) -> Option<GafferPacket> {
+      client.send(GafferPacket::new(sock.local_addr().unwrap(), payload)).unwrap();
+      thread::sleep(Duration::from_millis(20));
+      assert!(sock.recv().unwrap().is_none());
+      thread::sleep(Duration::from_millis(20));
+      assert!(client.recv().unwrap().is_none());
+      thread::sleep(Duration::from_millis(20));
+      assert!(sock.recv().unwrap().is_none());
+      client.update().unwrap();
+      thread::sleep(Duration::from_millis(20));
+      sock.recv().unwrap()
+    }
+
+    let mut config = GafferConfig::new();
+    config.max_connections = Some(1);
+    config.when_full = FullPolicy::EvictLeastRecent;
+    config.cookie_key = Some([5; 32]);
+    let mut sock = GafferSocket::bind_with_config("127.0.0.1:0", config).unwrap();
+    let mut first = GafferSocket::bind("127.0.0.1:0").unwrap();
+    let mut second = GafferSocket::bind("127.0.0.1:0").unwrap();
+    let first_addr = first.local_addr().unwrap();
+
+    assert_eq!(join(&mut sock, &mut first, vec![1; 40]).unwrap().payload, vec![1; 40]);
+    assert_eq!(join(&mut sock, &mut second, vec![2; 40]).unwrap().payload, vec![2; 40]);
+    assert_eq!(sock.stats().evicted, 1);
+    assert_eq!(sock.peers(), vec![second.local_addr().unwrap()]);
+    assert_eq!(sock.poll_event(), Some(GafferEvent::PeerEvicted(first_addr)));
+  }
+
+  #[test]
+  fn disconnects_peers() {
+    use event::GafferEvent;
+
+    let mut sock = GafferSocket::bind("127.0.0.1:0").unwrap();
+    let mut peer = GafferSocket::bind("127.0.0.1:0").unwrap();
+    let sock_addr = sock.local_addr().unwrap();
+    let peer_addr = peer.local_addr().unwrap();
+
+    peer.send(GafferPacket::new(sock_addr, vec![1])).unwrap();
+    thread::sleep(Duration::from_millis(20));
+    assert_eq!(sock.recv().unwrap().unwrap().payload, vec![1]);
+
+    sock.disconnect(peer_addr, DisconnectReason::Kicked).unwrap();
+    assert!(sock.connection(&peer_addr).is_none());
+    assert_eq!(sock.disconnect(peer_addr, DisconnectReason::Kicked).unwrap_err().kind(), io::ErrorKind::NotConnected);
+
+    thread::sleep(Duration::from_millis(20));
+    assert!(peer.recv().unwrap().is_none());
+    assert!(peer.connection(&sock_addr).is_none());
+    assert_eq!(peer.poll_event(), Some(GafferEvent::Disconnected(sock_addr, DisconnectReason::Kicked)));
+
+    // The other copies follow on later updates, and are dropped without bringing the
+    // connection back
+    assert!(sock.state.poll_control().is_none());
+    for copy in 1..DISCONNECT_COPIES {
+      let due = sock.state.next_farewell().unwrap();
+      thread::sleep(due.saturating_duration_since(Instant::now()));
+      sock.update().unwrap();
+      assert_eq!(sock.state.next_farewell().is_some(), copy + 1 < DISCONNECT_COPIES);
+    }
+    thread::sleep(Duration::from_millis(20));
+    assert!(peer.recv().unwrap().is_none());
+    assert!(peer.connection(&sock_addr).is_none());
+    assert_eq!(peer.poll_event(), None);
+  }
+
+  #[test]
+  fn control_datagrams_wait_behind_the_outbound_queue() {
+    use event::GafferEvent;
+
+    let mut sock = GafferSocket::bind("127.0.0.1:0").unwrap();
+    let mut peer = GafferSocket::bind("127.0.0.1:0").unwrap();
+    let sock_addr = sock.local_addr().unwrap();
+    let peer_addr = peer.local_addr().unwrap();
+
+    // Pretend an earlier send hit WouldBlock
+    let (_, blocked) = sock.state.preprocess_packet(GafferPacket::new(peer_addr, vec![1]));
+    sock.outbound.push_back((peer_addr, blocked));
+    let queued = sock.pending_bytes();
+    sock.disconnect(peer_addr, DisconnectReason::UserQuit).unwrap();
+    assert!(sock.pending_bytes() > queued);
+
+    assert_eq!(sock.flush().unwrap(), 0);
+    thread::sleep(Duration::from_millis(20));
+    assert_eq!(peer.recv().unwrap().unwrap().payload, vec![1]);
+    assert!(peer.recv().unwrap().is_none());
+    assert_eq!(peer.poll_event(), Some(GafferEvent::Disconnected(sock_addr, DisconnectReason::UserQuit)));
+  }
+
+  #[test]
+  fn shuts_down_from_an_event_loop() {
+    use event::GafferEvent;
+
+    let mut sock = GafferSocket::bind("127.0.0.1:0").unwrap();
+    let mut peer = GafferSocket::bind("127.0.0.1:0").unwrap();
+    let sock_addr = sock.local_addr().unwrap();
+    let peer_addr = peer.local_addr().unwrap();
+    assert_eq!(sock.poll_shutdown().unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+    sock.send(GafferPacket::new(peer_addr, vec![1])).unwrap();
+    sock.begin_shutdown(Duration::from_secs(5));
+    assert_eq!(sock.send(GafferPacket::new(peer_addr, vec![2])).unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+
+    let poll = Poll::new().unwrap();
+    poll.register(&sock, Token(0), Ready::readable(), PollOpt::level()).unwrap();
+    let mut events = Events::with_capacity(4);
+    let started = Instant::now();
+    let report = loop {
+      if let Some(report) = sock.poll_shutdown().unwrap() {
+        break report;
+      }
+      // The peer acks on its own updates
+      while let Ok(Some(packet)) = peer.recv() {
+        assert_eq!(packet.payload, vec![1]);
+      }
+      peer.update().unwrap();
+      poll.poll(&mut events, sock.next_shutdown_poll()).unwrap();
+    };
+    assert!(report.unconfirmed.is_empty());
+    assert!(started.elapsed() < Duration::from_secs(1));
+    assert_eq!(sock.next_shutdown_poll(), None);
+
+    thread::sleep(Duration::from_millis(20));
+    while let Ok(Some(_)) = peer.recv() {}
+    assert_eq!(peer.poll_event(), Some(GafferEvent::Disconnected(sock_addr, DisconnectReason::Shutdown)));
+  }
 }
@@ -0,0 +1,317 @@
+use std::io;
+
+use std::net::SocketAddr;
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+
+use std::thread::{self, JoinHandle};
+
+use std::time::{Duration, Instant};
+
+use addr::ToSingleSocketAddr;
+
+use config::GafferConfig;
+
+use event::GafferEvent;
+
+use filter::{AddressFilter, IpRange};
+
+use packet::{DisconnectReason, GafferPacket};
+
+use socket::{PartialSend, ShutdownReport};
+
+use socket::blocking::GafferSocket;
+
+/// How long the background thread waits on the socket before checking for outbound packets
+pub const DEFAULT_TICK: Duration = Duration::from_millis(5);
+
+/// A blocking `GafferSocket` run on its own thread
+///
+/// Packets are handed over through a `Sender<GafferPacket>` and everything received comes back
+/// as `GafferEvent`s, so the owning thread never blocks on the socket. The background thread
+/// calls `update` every tick and reports its failures as `GafferEvent::SendFailed`. It stops
+/// when this is dropped or shut down, handles from `sender` then refuse packets.
+///
+/// Changes to the filter, bans and disconnects are handed over the same way and apply from the
+/// next tick.
+pub struct ThreadedGafferSocket {
+  local_addr: SocketAddr,
+  outbound: Sender<GafferPacket>,
+  commands: Sender<Command>,
+  events: Receiver<GafferEvent>,
+  running: Arc<AtomicBool>,
+  thread: Option<JoinHandle<()>>,
+}
+
+impl ThreadedGafferSocket {
+  pub fn bind<A: ToSingleSocketAddr>(addr: A) -> io::Result<Self> {
+    ThreadedGafferSocket::bind_with_config(addr, GafferConfig::new())
+  }
+
+  /// Bind with socket options and protocol settings from `config`
+  pub fn bind_with_config<A: ToSingleSocketAddr>(addr: A, config: GafferConfig) -> io::Result<Self> {
+    GafferSocket::bind_with_config(addr, config).and_then(|socket| ThreadedGafferSocket::spawn(socket, DEFAULT_TICK))
+  }
+
+  /// Moves an existing socket onto a background thread
+  pub fn spawn(socket: GafferSocket, tick: Duration) -> io::Result<Self> {
+    socket.set_read_timeout(Some(tick))?;
+    let local_addr = socket.local_addr()?;
+    let (outbound_tx, outbound_rx) = mpsc::channel();
+    let (commands_tx, commands_rx) = mpsc::channel();
+    let (events_tx, events_rx) = mpsc::channel();
+    let running = Arc::new(AtomicBool::new(true));
+    let thread_running = running.clone();
+    let thread = thread::Builder::new()
+      .name("gaffer_udp".to_owned())
+      .spawn(move || run(socket, tick, outbound_rx, commands_rx, events_tx, thread_running))?;
+
+    Ok(ThreadedGafferSocket {
+      local_addr,
+      outbound: outbound_tx,
+      commands: commands_tx,
+      events: events_rx,
+      running,
+      thread: Some(thread),
+    })
+  }
+
+  /// The address the socket is bound to
+  pub fn local_addr(&self) -> SocketAddr {
+    self.local_addr
+  }
+
+  /// A handle for sending packets, may be cloned and moved to other threads
+  pub fn sender(&self) -> Sender<GafferPacket> {
+    self.outbound.clone()
+  }
+
+  /// The handle events arrive on
+  pub fn events(&self) -> &Receiver<GafferEvent> {
+    &self.events
+  }
+
+  /// Queue a packet for the background thread to send
+  pub fn send(&self, p: GafferPacket) -> io::Result<()> {
+    self.outbound.send(p)
+      .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "socket thread has stopped"))
+  }
+
+  /// Next event, if one has arrived
+  pub fn try_recv(&self) -> Option<GafferEvent> {
+    self.events.try_recv().ok()
+  }
+
+  /// Changes which addresses datagrams are accepted from, see `GafferState::filter_mut`
+  ///
+  /// `change` runs on the background thread.
+  pub fn update_filter<F>(&self, change: F) -> io::Result<()> where F: FnOnce(&mut AddressFilter) + Send + 'static {
+    self.command(Command::Filter(Box::new(change)))
+  }
+
+  /// Shuts a range out for `duration` and forgets its connections, see `GafferState::ban`
+  pub fn ban(&self, range: IpRange, duration: Duration) -> io::Result<()> {
+    self.command(Command::Ban(range, duration))
+  }
+
+  /// Tells a peer we are leaving and forgets it, see `blocking::GafferSocket::disconnect`
+  ///
+  /// Packets already handed over for it are sent first. Runs on the background thread, a peer
+  /// that is not connected is ignored.
+  pub fn disconnect(&self, addr: SocketAddr, reason: DisconnectReason) -> io::Result<()> {
+    self.command(Command::Disconnect(addr, reason))
+  }
+
+  /// Waits up to `timeout` for packets in flight to be confirmed, then stops the thread
+  ///
+  /// Packets already handed over are sent first, see `blocking::GafferSocket::shutdown`. Packets
+  /// received meanwhile are in the report rather than sent as events.
+  pub fn shutdown(self, timeout: Duration) -> io::Result<ShutdownReport> {
+    let (reply_tx, reply_rx) = mpsc::channel();
+    self.command(Command::Shutdown(timeout, reply_tx))?;
+    reply_rx.recv()
+      .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "socket thread has stopped"))?
+  }
+
+  fn command(&self, command: Command) -> io::Result<()> {
+    self.commands.send(command)
+      .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "socket thread has stopped"))
+  }
+}
+
+/// Changes to the socket made on the background thread
+enum Command {
+  Filter(Box<dyn FnOnce(&mut AddressFilter) + Send>),
+  Ban(IpRange, Duration),
+  Disconnect(SocketAddr, DisconnectReason),
+  Shutdown(Duration, Sender<io::Result<ShutdownReport>>),
+}
+
+impl Drop for ThreadedGafferSocket {
+  fn drop(&mut self) {
+    self.running.store(false, Ordering::SeqCst);
+    if let Some(thread) = self.thread.take() {
+      let _ = thread.join();
+    }
+  }
+}
+
+fn run(
+  mut socket: GafferSocket,
+  tick: Duration,
+  outbound: Receiver<GafferPacket>,
+  commands: Receiver<Command>,
+  events: Sender<GafferEvent>,
+  running: Arc<AtomicBool>
+) {
+  let mut last_update = Instant::now();
+  while running.load(Ordering::SeqCst) {
+    while let Ok(command) = commands.try_recv() {
+      match command {
+        Command::Filter(change) => change(socket.filter_mut()),
+        Command::Ban(range, duration) => {
+          socket.ban(range, duration);
+        },
+        Command::Disconnect(addr, reason) => {
+          send_handed_over(&mut socket, &outbound, &events);
+          let _ = socket.disconnect(addr, reason);
+        },
+        Command::Shutdown(timeout, reply) => {
+          send_handed_over(&mut socket, &outbound, &events);
+          let report = socket.shutdown(timeout);
+          while let Some(event) = socket.poll_event() {
+            let _ = events.send(event);
+          }
+          let _ = reply.send(report);
+          return;
+        },
+      }
+    }
+    loop {
+      match outbound.try_recv() {
+        Ok(packet) => {
+          let addr = packet.addr;
+          if let Err(err) = socket.send(packet) {
+            if events.send(GafferEvent::SendFailed(addr, err.kind())).is_err() {
+              return;
+            }
+          }
+        },
+        Err(TryRecvError::Empty) => break,
+        Err(TryRecvError::Disconnected) => return,
+      }
+    }
+
+    // Timeouts just mean nothing arrived this tick, and a malformed datagram is not worth
+    // stopping for
+    if let Ok(packet) = socket.recv() {
+      if events.send(GafferEvent::Packet(packet)).is_err() {
+        return;
+      }
+    }
+    while let Some(event) = socket.poll_event() {
+      if events.send(event).is_err() {
+        return;
+      }
+    }
+
+    if last_update.elapsed() >= tick {
+      if let Err(err) = socket.update() {
+        if let Some(partial) = err.get_ref().and_then(|cause| cause.downcast_ref::<PartialSend>()) {
+          if events.send(GafferEvent::SendFailed(partial.addr, err.kind())).is_err() {
+            return;
+          }
+        }
+      }
+      last_update = Instant::now();
+    }
+  }
+}
+
+/// Sends every packet already handed over, so a command issued after them does not overtake them
+fn send_handed_over(socket: &mut GafferSocket, outbound: &Receiver<GafferPacket>, events: &Sender<GafferEvent>) {
+  while let Ok(packet) = outbound.try_recv() {
+    let addr = packet.addr;
+    if let Err(err) = socket.send(packet) {
+      let _ = events.send(GafferEvent::SendFailed(addr, err.kind()));
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use event::GafferEvent;
+  use packet::GafferPacket;
+
+  #[test]
+  fn exchanges_packets_through_handles() {
+    let sender = ThreadedGafferSocket::bind("127.0.0.1:45226").unwrap();
+    let receiver = ThreadedGafferSocket::bind("127.0.0.1:45227").unwrap();
+
+    sender.sender().send(GafferPacket::new("127.0.0.1:45227", vec![1, 2, 3])).unwrap();
+
+    let event = receiver.events().recv_timeout(Duration::from_secs(1)).unwrap();
+    assert_eq!(event, GafferEvent::Packet(GafferPacket::new("127.0.0.1:45226", vec![1, 2, 3])));
+  }
+
+  #[test]
+  fn changes_the_filter_at_runtime() {
+    let socket = ThreadedGafferSocket::bind("127.0.0.1:0").unwrap();
+    let sock_addr = socket.local_addr();
+    let peer = ThreadedGafferSocket::bind("127.0.0.1:0").unwrap();
+
+    socket.ban("127.0.0.1".parse().unwrap(), Duration::from_secs(60)).unwrap();
+    thread::sleep(Duration::from_millis(50));
+    peer.send(GafferPacket::new(sock_addr, vec![1])).unwrap();
+    assert!(socket.events().recv_timeout(Duration::from_millis(200)).is_err());
+
+    socket.update_filter(|filter| {
+      filter.unban(&"127.0.0.1".parse().unwrap());
+    }).unwrap();
+    thread::sleep(Duration::from_millis(50));
+    peer.send(GafferPacket::new(sock_addr, vec![2])).unwrap();
+    let event = socket.events().recv_timeout(Duration::from_secs(1)).unwrap();
+    assert_eq!(event, GafferEvent::Packet(GafferPacket::new(peer.local_addr(), vec![2])));
+  }
+
+  #[test]
+  fn disconnects_peers() {
+    let socket = ThreadedGafferSocket::bind("127.0.0.1:0").unwrap();
+    let peer = ThreadedGafferSocket::bind("127.0.0.1:0").unwrap();
+
+    socket.send(GafferPacket::new(peer.local_addr(), vec![1])).unwrap();
+    socket.disconnect(peer.local_addr(), DisconnectReason::Kicked).unwrap();
+
+    let event = peer.events().recv_timeout(Duration::from_secs(1)).unwrap();
+    assert_eq!(event, GafferEvent::Packet(GafferPacket::new(socket.local_addr(), vec![1])));
+    let event = peer.events().recv_timeout(Duration::from_secs(1)).unwrap();
+    assert_eq!(event, GafferEvent::Disconnected(socket.local_addr(), DisconnectReason::Kicked));
+  }
+
+  #[test]
+  fn shuts_down_once_confirmed() {
+    let socket = ThreadedGafferSocket::bind("127.0.0.1:0").unwrap();
+    let peer = ThreadedGafferSocket::bind("127.0.0.1:0").unwrap();
+    let handle = socket.sender();
+
+    socket.send(GafferPacket::new(peer.local_addr(), vec![1])).unwrap();
+    let event = peer.events().recv_timeout(Duration::from_secs(1)).unwrap();
+    assert_eq!(event, GafferEvent::Packet(GafferPacket::new(socket.local_addr(), vec![1])));
+
+    // The peer's thread acks on its own
+    let report = socket.shutdown(Duration::from_secs(5)).unwrap();
+    assert!(report.unconfirmed.is_empty());
+    assert!(handle.send(GafferPacket::new(peer.local_addr(), vec![2])).is_err());
+  }
+
+  #[test]
+  fn stops_when_dropped() {
+    let socket = ThreadedGafferSocket::bind("127.0.0.1:45228").unwrap();
+    let handle = socket.sender();
+    drop(socket);
+    assert!(handle.send(GafferPacket::new("127.0.0.1:45229", vec![1])).is_err());
+  }
+}
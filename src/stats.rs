@@ -0,0 +1,29 @@
+/// Counters for received traffic the socket could not use
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GafferStats {
+  /// Datagrams larger than the receive buffer, discarded rather than delivered cut short
+  pub truncated: u64,
+  /// Datagrams whose sequence number was already seen, or too old to tell
+  pub replayed: u64,
+  /// Datagrams whose sequence number ran further ahead than `GafferConfig::max_sequence_jump`
+  pub out_of_window: u64,
+  /// Resent payloads dropped because their first copy was already delivered
+  pub duplicates: u64,
+  /// Datagrams from addresses the filter shuts out
+  pub filtered: u64,
+  /// Datagrams dropped for going over a rate limit
+  pub rate_limited: u64,
+  /// Datagrams that failed authentication, or came from a peer without keys when encryption is
+  /// required
+  pub unauthenticated: u64,
+  /// Datagrams from new addresses answered with a challenge cookie
+  pub challenged: u64,
+  /// Datagrams from new addresses too small to answer without amplifying them
+  pub unchallenged: u64,
+  /// Echoed cookies that were forged, expired or meant for another address
+  pub bad_cookies: u64,
+  /// New peers turned away because `GafferConfig::max_connections` was reached
+  pub rejected_full: u64,
+  /// Connections forgotten to make room for a new peer
+  pub evicted: u64,
+}
@@ -0,0 +1,188 @@
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use chacha20poly1305::{ChaCha20Poly1305, Key, KeyInit, Nonce, Tag};
+use chacha20poly1305::aead::AeadInPlace;
+
+use crypto::PacketKeys;
+
+use std::io::{self, Cursor, Read};
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Most server addresses a token can name
+pub const MAX_TOKEN_SERVERS: usize = 8;
+
+/// Authenticated along with every token, so a token only decrypts as this version of one
+const TOKEN_AAD: &[u8] = b"gaffer_udp connect token 1";
+
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+
+/// Permission for one client to connect to a set of servers, as issued by a backend
+///
+/// The backend hands the whole token to an authenticated client over a secure channel. The
+/// client keeps `keys` and sends `private` to a server, which can decrypt it with the shared
+/// token key and so trusts everything inside without asking the backend.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConnectToken {
+  pub client_id: u64,
+  /// Seconds since the unix epoch after which servers refuse the token
+  pub expires_at: u64,
+  pub server_addrs: Vec<SocketAddr>,
+  /// Session keys from the client's side
+  pub keys: PacketKeys,
+  /// The encrypted and authenticated copy of the above, for the server
+  pub private: Vec<u8>,
+}
+
+/// What a server learns from a valid token
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PrivateToken {
+  pub client_id: u64,
+  pub expires_at: u64,
+  pub server_addrs: Vec<SocketAddr>,
+  /// Session keys from the server's side
+  pub keys: PacketKeys,
+  /// Unique per token, used to stop one token being used from two addresses
+  pub nonce: [u8; NONCE_SIZE],
+}
+
+impl PrivateToken {
+  /// Whether the token names `local`, a wildcard local address matches on port alone
+  pub fn names_server(&self, local: &SocketAddr) -> bool {
+    self.server_addrs.iter().any(|addr| {
+      addr.port() == local.port() && (local.ip().is_unspecified() || addr.ip() == local.ip())
+    })
+  }
+}
+
+/// Issues connect tokens, meant to live in the backend that authenticates players
+///
+/// Session keys are taken as given, generate them with a CSPRNG.
+pub struct TokenGenerator {
+  cipher: ChaCha20Poly1305,
+  sequence: u32,
+}
+
+impl TokenGenerator {
+  /// `key` is shared with every server that should accept the tokens
+  pub fn new(key: &[u8; 32]) -> TokenGenerator {
+    TokenGenerator { cipher: ChaCha20Poly1305::new(Key::from_slice(key)), sequence: 0 }
+  }
+
+  pub fn generate(
+    &mut self,
+    client_id: u64,
+    server_addrs: &[SocketAddr],
+    valid_for: Duration,
+    keys: PacketKeys
+  ) -> io::Result<ConnectToken> {
+    if server_addrs.is_empty() || server_addrs.len() > MAX_TOKEN_SERVERS {
+      return Err(io::Error::new(io::ErrorKind::InvalidInput, "a token names between 1 and 8 servers"));
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)
+      .map_err(|_| io::Error::other("system clock is before the unix epoch"))?;
+    let expires_at = now.as_secs() + valid_for.as_secs();
+
+    // The creation time and a counter keep nonces unique across restarts of the generator
+    let mut nonce = [0; NONCE_SIZE];
+    nonce[..8].copy_from_slice(&(now.as_nanos() as u64).to_be_bytes());
+    nonce[8..].copy_from_slice(&self.sequence.to_be_bytes());
+    self.sequence = self.sequence.wrapping_add(1);
+
+    let mut private = nonce.to_vec();
+    let mut body = Vec::new();
+    body.write_u64::<BigEndian>(client_id)?;
+    body.write_u64::<BigEndian>(expires_at)?;
+    body.extend_from_slice(&keys.send);
+    body.extend_from_slice(&keys.recv);
+    body.write_u8(server_addrs.len() as u8)?;
+    for addr in server_addrs {
+      write_addr(&mut body, addr)?;
+    }
+    let tag = self.cipher.encrypt_in_place_detached(Nonce::from_slice(&nonce), TOKEN_AAD, &mut body)
+      .map_err(|_| io::Error::other("could not encrypt token"))?;
+    private.extend_from_slice(&body);
+    private.extend_from_slice(&tag);
+
+    Ok(ConnectToken { client_id, expires_at, server_addrs: server_addrs.to_vec(), keys, private })
+  }
+}
+
+/// Checks and decrypts the private part of a token, refusing it once expired
+pub fn open_token(key: &[u8; 32], private: &[u8], now: SystemTime) -> io::Result<PrivateToken> {
+  if private.len() < NONCE_SIZE + TAG_SIZE {
+    return Err(invalid_token());
+  }
+  let (nonce, rest) = private.split_at(NONCE_SIZE);
+  let (body, tag) = rest.split_at(rest.len() - TAG_SIZE);
+  let mut body = body.to_vec();
+  ChaCha20Poly1305::new(Key::from_slice(key))
+    .decrypt_in_place_detached(Nonce::from_slice(nonce), TOKEN_AAD, &mut body, Tag::from_slice(tag))
+    .map_err(|_| invalid_token())?;
+
+  let mut rdr = Cursor::new(body);
+  let client_id = rdr.read_u64::<BigEndian>()?;
+  let expires_at = rdr.read_u64::<BigEndian>()?;
+  let mut client_to_server = [0; 32];
+  let mut server_to_client = [0; 32];
+  rdr.read_exact(&mut client_to_server)?;
+  rdr.read_exact(&mut server_to_client)?;
+  let count = rdr.read_u8()? as usize;
+  if count > MAX_TOKEN_SERVERS {
+    return Err(invalid_token());
+  }
+  let server_addrs = (0..count).map(|_| read_addr(&mut rdr)).collect::<io::Result<Vec<_>>>()?;
+
+  let now = now.duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or(0);
+  if now >= expires_at {
+    return Err(io::Error::new(io::ErrorKind::PermissionDenied, "connect token has expired"));
+  }
+
+  let mut token_nonce = [0; NONCE_SIZE];
+  token_nonce.copy_from_slice(nonce);
+  Ok(PrivateToken {
+    client_id,
+    expires_at,
+    server_addrs,
+    keys: PacketKeys::new(server_to_client, client_to_server),
+    nonce: token_nonce,
+  })
+}
+
+fn invalid_token() -> io::Error {
+  io::Error::new(io::ErrorKind::PermissionDenied, "connect token is not valid")
+}
+
+fn write_addr(wtr: &mut Vec<u8>, addr: &SocketAddr) -> io::Result<()> {
+  match addr.ip() {
+    IpAddr::V4(ip) => {
+      wtr.write_u8(4)?;
+      wtr.extend_from_slice(&ip.octets());
+    },
+    IpAddr::V6(ip) => {
+      wtr.write_u8(6)?;
+      wtr.extend_from_slice(&ip.octets());
+    },
+  }
+  wtr.write_u16::<BigEndian>(addr.port())
+}
+
+fn read_addr(rdr: &mut Cursor<Vec<u8>>) -> io::Result<SocketAddr> {
+  let ip = match rdr.read_u8()? {
+    4 => {
+      let mut octets = [0; 4];
+      rdr.read_exact(&mut octets)?;
+      IpAddr::V4(Ipv4Addr::from(octets))
+    },
+    6 => {
+      let mut octets = [0; 16];
+      rdr.read_exact(&mut octets)?;
+      IpAddr::V6(Ipv6Addr::from(octets))
+    },
+    _ => return Err(invalid_token()),
+  };
+  Ok(SocketAddr::new(ip, rdr.read_u16::<BigEndian>()?))
+}